@@ -1,16 +1,65 @@
 //! Build script for the Neotron SDK
 //!
 //! Sets up Rust to link with a Cortex-M linker script if you are building for
-//! an Arm bare-metal target.
+//! an Arm bare-metal target. The script is generated from
+//! `neotron-cortex-m.ld`, with the Transient Program Area (TPA) set for the
+//! board selected with a `board-*` cargo feature, or from the
+//! `NEOTRON_TPA_START` and `NEOTRON_TPA_SIZE` environment variables. The
+//! stack reserved at the top of the TPA is set by `NEOTRON_STACK_SIZE`.
+//!
+//! Also passes the CPU clock speed (from the `NEOTRON_CPU_HZ` environment
+//! variable) through to the SDK, so that `delay()` can be calibrated.
 
 use std::io::prelude::*;
 
+/// The CPU clock speed we assume if `NEOTRON_CPU_HZ` is not set.
+///
+/// This is the rated clock speed of the RP2040 used in the Neotron Pico.
+const DEFAULT_CPU_HZ: u32 = 133_000_000;
+
+/// The line in `neotron-cortex-m.ld` which sets out the TPA.
+const TPA_LINE: &str = "RAM   (rwx) : ORIGIN = 0x20001000, LENGTH = 256K";
+
+/// The TPA on each board we know about, and the cargo feature that selects it
+/// (as Cargo tells a build script about it).
+const BOARDS: &[(&str, Tpa)] = &[(
+    "CARGO_FEATURE_BOARD_NEOTRON_PICO",
+    Tpa {
+        start: 0x2000_1000,
+        size: 256 * 1024,
+    },
+)];
+
+/// The TPA we use if no board is selected - the Neotron Pico's.
+const DEFAULT_TPA: Tpa = BOARDS[0].1;
+
+/// The line in `neotron-cortex-m.ld` which sets the size of the stack.
+const STACK_LINE: &str = "__neotron_stack_size = 16K;";
+
+/// A Transient Program Area - the block of RAM an application is loaded into.
+#[derive(Debug, Clone, Copy)]
+struct Tpa {
+    start: u32,
+    size: u32,
+}
+
 fn main() {
     let arch = std::env::var("CARGO_CFG_TARGET_ARCH").expect("CARGO_CFG_TARGET_ARCH variable");
     let os = std::env::var("CARGO_CFG_TARGET_OS").expect("CARGO_CFG_TARGET_OS variable");
+    let target = std::env::var("TARGET").expect("TARGET variable");
+    println!("cargo:rustc-check-cfg=cfg(has_dwt_cyccnt)");
     match (arch.as_str(), os.as_str()) {
         ("arm", "none") => {
             setup_cortexm_linker();
+            setup_cpu_clock();
+            // Armv7-M and Armv8-M Mainline have a DWT cycle counter. Armv6-M
+            // and Armv8-M Baseline do not.
+            if target.starts_with("thumbv7m")
+                || target.starts_with("thumbv7em")
+                || target.starts_with("thumbv8m.main")
+            {
+                println!("cargo:rustc-cfg=has_dwt_cyccnt");
+            }
         }
         _ => {
             // no script required
@@ -18,19 +67,147 @@ fn main() {
     }
 }
 
+fn setup_cpu_clock() {
+    let cpu_hz = match std::env::var("NEOTRON_CPU_HZ") {
+        Ok(value) => value
+            .replace('_', "")
+            .parse::<u32>()
+            .expect("NEOTRON_CPU_HZ should be a clock speed in Hz"),
+        Err(_) => DEFAULT_CPU_HZ,
+    };
+    if cpu_hz == 0 {
+        panic!("NEOTRON_CPU_HZ cannot be zero");
+    }
+    println!("cargo:rustc-env=NEOTRON_CPU_HZ={}", cpu_hz);
+    println!("cargo:rerun-if-env-changed=NEOTRON_CPU_HZ");
+}
+
 fn setup_cortexm_linker() {
-    // Put `neotron-cortex-m.ld` in our output directory and ensure it's
-    // on the linker search path.
+    // Put `neotron-cortex-m.ld`, with the right TPA, in our output directory
+    // and ensure it's on the linker search path.
+    let tpa = select_tpa();
+    let script = include_str!("./neotron-cortex-m.ld");
+    assert!(
+        script.contains(TPA_LINE),
+        "neotron-cortex-m.ld should contain {:?}",
+        TPA_LINE
+    );
+    assert!(
+        script.contains(STACK_LINE),
+        "neotron-cortex-m.ld should contain {:?}",
+        STACK_LINE
+    );
+    let mut script = script.replace(
+        TPA_LINE,
+        &format!(
+            "RAM   (rwx) : ORIGIN = {:#010x}, LENGTH = {:#x}",
+            tpa.start, tpa.size
+        ),
+    );
+    if let Ok(value) = std::env::var("NEOTRON_STACK_SIZE") {
+        let stack_size =
+            parse_size(&value).expect("NEOTRON_STACK_SIZE should be a size, like 16K or 0x4000");
+        if stack_size >= tpa.size {
+            panic!("NEOTRON_STACK_SIZE should be smaller than the TPA");
+        }
+        if stack_size & 7 != 0 {
+            panic!("NEOTRON_STACK_SIZE should be a multiple of 8");
+        }
+        script = script.replace(
+            STACK_LINE,
+            &format!("__neotron_stack_size = {:#x};", stack_size),
+        );
+    }
     let out = &std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     std::fs::File::create(out.join("neotron-cortex-m.ld"))
         .unwrap()
-        .write_all(include_bytes!("./neotron-cortex-m.ld"))
+        .write_all(script.as_bytes())
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    // A build script can't pass `-T` to the linker when an application is
+    // linked - `cargo:rustc-link-arg` only applies to this crate's own
+    // targets, never to the application that depends on us. But a library is
+    // passed to the final link, and if the linker doesn't recognise a library
+    // as an object file, it reads it as a linker script. So we link the
+    // script as a library.
+    //
+    // That trick doesn't work for `--nmagic`, which is a command-line option
+    // and can't go in a linker script, so we can't pass it either. The
+    // script's `PHDRS` command keeps the ELF headers out of the loaded
+    // segments, so applications still load without it - but the file is
+    // bigger, because the linker starts the code 4 KiB into the file and
+    // aligns each segment to 64 KiB. Applications which want the smaller file
+    // have to put `-Clink-arg=--nmagic` in their own `rustflags`.
+    //
+    // Applications which still pass `-Tneotron-cortex-m.ld` in their
+    // `rustflags` get the generated script that way instead - giving it to
+    // the linker twice would be an error.
+    let rustflags = std::env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    if !rustflags
+        .split('\x1f')
+        .any(|flag| flag.ends_with("-Tneotron-cortex-m.ld"))
+    {
+        println!("cargo:rustc-link-lib=static:-bundle,+verbatim=neotron-cortex-m.ld");
+    }
+
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `neotron-cortex-m.ld`
     // here, we ensure the build script is only re-run when
     // `neotron-cortex-m.ld` is changed.
     println!("cargo:rerun-if-changed=./neotron-cortex-m.ld");
+    println!("cargo:rerun-if-env-changed=NEOTRON_TPA_START");
+    println!("cargo:rerun-if-env-changed=NEOTRON_TPA_SIZE");
+    println!("cargo:rerun-if-env-changed=NEOTRON_STACK_SIZE");
+}
+
+/// Work out where the TPA is.
+///
+/// The environment variables win over the board features, which win over the
+/// default.
+fn select_tpa() -> Tpa {
+    let mut boards = BOARDS
+        .iter()
+        .filter(|(feature, _)| std::env::var_os(feature).is_some());
+    let mut tpa = match (boards.next(), boards.next()) {
+        (Some((_, tpa)), None) => *tpa,
+        (None, _) => DEFAULT_TPA,
+        (Some(_), Some(_)) => panic!("Only one `board-*` feature can be enabled"),
+    };
+    if let Ok(value) = std::env::var("NEOTRON_TPA_START") {
+        tpa.start =
+            parse_size(&value).expect("NEOTRON_TPA_START should be an address, like 0x20001000");
+    }
+    if let Ok(value) = std::env::var("NEOTRON_TPA_SIZE") {
+        tpa.size =
+            parse_size(&value).expect("NEOTRON_TPA_SIZE should be a size, like 256K or 0x40000");
+    }
+    if tpa.size == 0 {
+        panic!("NEOTRON_TPA_SIZE cannot be zero");
+    }
+    if tpa.start % 4 != 0 {
+        panic!("NEOTRON_TPA_START should be a multiple of 4");
+    }
+    if tpa.start.checked_add(tpa.size - 1).is_none() {
+        panic!("The TPA cannot go past the end of memory");
+    }
+    tpa
+}
+
+/// Parse a number like `65536`, `0x10000`, `64K` or `1M`.
+fn parse_size(value: &str) -> Option<u32> {
+    let value = value.trim().replace('_', "");
+    let (digits, multiplier) = if let Some(digits) = value.strip_suffix(['K', 'k']) {
+        (digits, 1024)
+    } else if let Some(digits) = value.strip_suffix(['M', 'm']) {
+        (digits, 1024 * 1024)
+    } else {
+        (value.as_str(), 1)
+    };
+    let number = if let Some(hex) = digits.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()?
+    } else {
+        digits.parse::<u32>().ok()?
+    };
+    number.checked_mul(multiplier)
 }
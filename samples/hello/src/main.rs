@@ -1,13 +1,9 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
-#[cfg(not(target_os = "none"))]
-fn main() {
-    neotron_sdk::init();
-}
+neotron_sdk::entry!(real_main);
 
-#[no_mangle]
-extern "C" fn neotron_main() -> i32 {
+fn real_main() -> i32 {
     let stdout = neotron_sdk::stdout();
     if stdout.write(b"Hello, world\n").is_ok() {
         0
@@ -3,21 +3,7 @@
 
 use core::fmt::Write;
 
-#[cfg(not(target_os = "none"))]
-fn main() {
-    neotron_sdk::init();
-}
-
-#[no_mangle]
-extern "C" fn neotron_main() -> i32 {
-    if let Err(e) = real_main() {
-        let mut stdout = neotron_sdk::stdout();
-        let _ = writeln!(stdout, "Error: {:?}", e);
-        1
-    } else {
-        0
-    }
-}
+neotron_sdk::entry!(real_main);
 
 fn real_main() -> Result<(), neotron_sdk::Error> {
     let mut stdout = neotron_sdk::stdout();
@@ -30,6 +16,11 @@ fn real_main() -> Result<(), neotron_sdk::Error> {
     let stat = f.stat()?;
     let mut bytes_remaining = stat.file_size;
     let _ = writeln!(stdout, "File is {} bytes", bytes_remaining);
+    let _ = writeln!(
+        stdout,
+        "Last modified {}",
+        neotron_sdk::time::Iso8601(stat.mtime)
+    );
 
     let mut lines_remaining = 24;
     let mut buffer = [0u8; 16];
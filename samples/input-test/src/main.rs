@@ -1,21 +1,21 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
-#[cfg(not(target_os = "none"))]
-fn main() {
-    neotron_sdk::init();
-}
-
 use core::fmt::Write;
 
-#[no_mangle]
-extern "C" fn neotron_main() -> i32 {
+neotron_sdk::entry!(real_main);
+
+fn real_main() -> i32 {
     let mut stdout = neotron_sdk::stdout();
     let stdin = neotron_sdk::stdin();
     let _ = stdout.write(b"Type some things, press Ctrl-X to quit...\n");
     loop {
         let mut buffer = [0u8; 16];
         match stdin.read(&mut buffer) {
+            Err(neotron_sdk::Error::EndOfFile) => {
+                // Input was piped in, and has run out
+                return 0;
+            }
             Err(_) => {
                 return 1;
             }
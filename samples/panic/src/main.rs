@@ -1,16 +1,19 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
-#[cfg(not(target_os = "none"))]
-fn main() {
-    neotron_sdk::init();
-}
-
 use core::fmt::Write;
 
-#[no_mangle]
-extern "C" fn neotron_main() -> i32 {
+neotron_sdk::entry!(real_main);
+
+fn real_main() -> i32 {
+    neotron_sdk::set_panic_hook(panic_hook);
     let stdout = neotron_sdk::stdout();
     writeln!(&stdout, "About to panic...\n").unwrap();
     panic!("Oh no, I panicked!");
 }
+
+fn panic_hook(_info: &neotron_sdk::PanicInfo) {
+    let mut stdout = neotron_sdk::stdout();
+    neotron_sdk::console::set_sgr(&mut stdout, [neotron_sdk::console::SgrParam::Reset]);
+    neotron_sdk::console::cursor_on(&mut stdout);
+}
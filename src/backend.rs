@@ -0,0 +1,477 @@
+//! Pluggable OS backends
+//!
+//! On Neotron OS, the OS gives the application a table of `extern "C"`
+//! functions (an [`Api`]). The [`Backend`] trait lets you write the other side
+//! of that table in ordinary Rust, with one method per [`Api`] entry. Every
+//! method has a default implementation which returns
+//! [`Error::Unimplemented`], so you only need to write the ones you care
+//! about.
+//!
+//! On UNIX and Windows, pass your backend to
+//! [`init_with_backend`](crate::init_with_backend) instead of calling
+//! [`init`](crate::init), and the application will talk to your backend
+//! instead of the built-in fake OS. This lets you plug in emulated devices, or
+//! mocks for testing. On UNIX and Windows, the `fault` module has a backend
+//! which wraps another backend and makes some of its calls fail, and the
+//! `record` module can play back a log of calls recorded earlier.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use neotron_api::{FfiBuffer, FfiByteSlice, FfiString};
+
+use crate::{Api, Error, Result};
+
+pub use neotron_api::{dir, file};
+
+#[cfg(not(target_os = "none"))]
+pub mod fault;
+
+#[cfg(any(feature = "record", not(target_os = "none")))]
+pub mod record;
+
+#[cfg(feature = "trace")]
+pub mod trace;
+
+// ============================================================================
+// Static Variables
+// ============================================================================
+
+/// The backend that the installed [`Adapter`] table calls.
+///
+/// This is a `&'static B`, for whichever `B` the [`Adapter`] was built for.
+static BACKEND: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// An implementation of the Neotron OS API.
+///
+/// Each method corresponds to the field of the same name in [`Api`], but with
+/// Rust types instead of FFI-safe types. See [`Api`] for what each one should
+/// do.
+///
+/// Methods take `&self`, because an application may call the API from
+/// anywhere. Use a `Mutex` or atomics for any state you need to change.
+pub trait Backend: Sync + 'static {
+    /// Open a file, given a path.
+    fn open(&self, _path: &str, _flags: file::Flags) -> Result<file::Handle> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Close a previously opened file.
+    fn close(&self, _fd: file::Handle) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Write to an open file handle, blocking until everything is written.
+    fn write(&self, _fd: file::Handle, _buffer: &[u8]) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Read from an open file, returning how much was actually read.
+    fn read(&self, _fd: file::Handle, _buffer: &mut [u8]) -> Result<usize> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Move the file offset to the given position.
+    fn seek_set(&self, _fd: file::Handle, _position: u64) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Move the file offset relative to the current position.
+    fn seek_cur(&self, _fd: file::Handle, _offset: i64) -> Result<u64> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Move the file offset to the end of the file.
+    fn seek_end(&self, _fd: file::Handle) -> Result<u64> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Rename a file.
+    fn rename(&self, _old_path: &str, _new_path: &str) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Perform a special I/O control operation.
+    fn ioctl(&self, _fd: file::Handle, _command: u64, _value: u64) -> Result<u64> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Open a directory, given a path.
+    fn opendir(&self, _path: &str) -> Result<dir::Handle> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Close a previously opened directory.
+    fn closedir(&self, _dir: dir::Handle) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Read from an open directory.
+    fn readdir(&self, _dir: dir::Handle) -> Result<dir::Entry> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Get information about a file.
+    fn stat(&self, _path: &str) -> Result<file::Stat> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Get information about an open file.
+    fn fstat(&self, _fd: file::Handle) -> Result<file::Stat> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Delete a file.
+    fn deletefile(&self, _path: &str) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Delete a directory.
+    fn deletedir(&self, _path: &str) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Change the current directory.
+    fn chdir(&self, _path: &str) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Change the current directory to the open directory.
+    fn dchdir(&self, _dir: dir::Handle) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Write the current directory into `buffer`, returning how many bytes
+    /// were written.
+    fn pwd(&self, _buffer: &mut [u8]) -> Result<usize> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Allocate some memory.
+    fn malloc(&self, _size: usize, _alignment: usize) -> Result<*mut core::ffi::c_void> {
+        Err(Error::Unimplemented)
+    }
+
+    /// Free some previously allocated memory.
+    fn free(&self, _ptr: *mut core::ffi::c_void, _size: usize, _alignment: usize) {}
+}
+
+/// Builds an [`Api`] table which calls a [`Backend`] of type `B`.
+///
+/// The functions in the table call whichever backend was given to
+/// [`install`], so the table must only be used while a `B` is installed.
+struct Adapter<B>(core::marker::PhantomData<B>);
+
+impl<B: Backend> Adapter<B> {
+    /// The table of functions, which calls the installed `B`.
+    const API: Api = Api {
+        open: Self::open,
+        close: Self::close,
+        write: Self::write,
+        read: Self::read,
+        seek_set: Self::seek_set,
+        seek_cur: Self::seek_cur,
+        seek_end: Self::seek_end,
+        rename: Self::rename,
+        ioctl: Self::ioctl,
+        opendir: Self::opendir,
+        closedir: Self::closedir,
+        readdir: Self::readdir,
+        stat: Self::stat,
+        fstat: Self::fstat,
+        deletefile: Self::deletefile,
+        deletedir: Self::deletedir,
+        chdir: Self::chdir,
+        dchdir: Self::dchdir,
+        pwd: Self::pwd,
+        malloc: Self::malloc,
+        free: Self::free,
+    };
+
+    /// Get the installed backend.
+    fn backend() -> &'static B {
+        let ptr = BACKEND.load(Ordering::Relaxed) as *const B;
+        // Safety: `install` stores a `&'static B` along with this table, and
+        // its caller promises no OS call happens while it's halfway through,
+        // so if we're being called, it's a `B`.
+        unsafe { &*ptr }
+    }
+
+    extern "C" fn open(path: FfiString, flags: file::Flags) -> neotron_api::Result<file::Handle> {
+        Self::backend().open(path.as_str(), flags).into()
+    }
+
+    extern "C" fn close(fd: file::Handle) -> neotron_api::Result<()> {
+        Self::backend().close(fd).into()
+    }
+
+    extern "C" fn write(fd: file::Handle, buffer: FfiByteSlice) -> neotron_api::Result<()> {
+        Self::backend().write(fd, buffer.as_slice()).into()
+    }
+
+    extern "C" fn read(fd: file::Handle, mut buffer: FfiBuffer) -> neotron_api::Result<usize> {
+        let buffer = buffer.as_mut_slice().unwrap_or(&mut []);
+        Self::backend().read(fd, buffer).into()
+    }
+
+    extern "C" fn seek_set(fd: file::Handle, position: u64) -> neotron_api::Result<()> {
+        Self::backend().seek_set(fd, position).into()
+    }
+
+    extern "C" fn seek_cur(fd: file::Handle, offset: i64) -> neotron_api::Result<u64> {
+        Self::backend().seek_cur(fd, offset).into()
+    }
+
+    extern "C" fn seek_end(fd: file::Handle) -> neotron_api::Result<u64> {
+        Self::backend().seek_end(fd).into()
+    }
+
+    extern "C" fn rename(old_path: FfiString, new_path: FfiString) -> neotron_api::Result<()> {
+        Self::backend()
+            .rename(old_path.as_str(), new_path.as_str())
+            .into()
+    }
+
+    extern "C" fn ioctl(fd: file::Handle, command: u64, value: u64) -> neotron_api::Result<u64> {
+        Self::backend().ioctl(fd, command, value).into()
+    }
+
+    extern "C" fn opendir(path: FfiString) -> neotron_api::Result<dir::Handle> {
+        Self::backend().opendir(path.as_str()).into()
+    }
+
+    extern "C" fn closedir(dir: dir::Handle) -> neotron_api::Result<()> {
+        Self::backend().closedir(dir).into()
+    }
+
+    extern "C" fn readdir(dir: dir::Handle) -> neotron_api::Result<dir::Entry> {
+        Self::backend().readdir(dir).into()
+    }
+
+    extern "C" fn stat(path: FfiString) -> neotron_api::Result<file::Stat> {
+        Self::backend().stat(path.as_str()).into()
+    }
+
+    extern "C" fn fstat(fd: file::Handle) -> neotron_api::Result<file::Stat> {
+        Self::backend().fstat(fd).into()
+    }
+
+    extern "C" fn deletefile(path: FfiString) -> neotron_api::Result<()> {
+        Self::backend().deletefile(path.as_str()).into()
+    }
+
+    extern "C" fn deletedir(path: FfiString) -> neotron_api::Result<()> {
+        Self::backend().deletedir(path.as_str()).into()
+    }
+
+    extern "C" fn chdir(path: FfiString) -> neotron_api::Result<()> {
+        Self::backend().chdir(path.as_str()).into()
+    }
+
+    extern "C" fn dchdir(dir: dir::Handle) -> neotron_api::Result<()> {
+        Self::backend().dchdir(dir).into()
+    }
+
+    extern "C" fn pwd(mut buffer: FfiBuffer) -> neotron_api::Result<usize> {
+        let buffer = buffer.as_mut_slice().unwrap_or(&mut []);
+        Self::backend().pwd(buffer).into()
+    }
+
+    extern "C" fn malloc(
+        size: usize,
+        alignment: usize,
+    ) -> neotron_api::Result<*mut core::ffi::c_void> {
+        Self::backend().malloc(size, alignment).into()
+    }
+
+    extern "C" fn free(ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        Self::backend().free(ptr, size, alignment)
+    }
+}
+
+/// A [`Backend`] which calls an [`Api`] table, such as the one given to the
+/// application by Neotron OS.
+///
+/// This lets you wrap the real OS in another backend (like a
+/// tracer, or a fault injector).
+pub struct ApiBackend {
+    api: AtomicPtr<Api>,
+}
+
+impl ApiBackend {
+    /// Create a backend with no table. Call [`ApiBackend::set`] before using
+    /// it.
+    pub const fn new() -> ApiBackend {
+        ApiBackend {
+            api: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Set the table to call.
+    pub fn set(&self, api: &'static Api) {
+        self.api
+            .store(api as *const Api as *mut Api, Ordering::Relaxed);
+    }
+
+    /// Get the table to call.
+    ///
+    /// Panics if [`ApiBackend::set`] hasn't been called.
+    fn api(&self) -> &'static Api {
+        let ptr = self.api.load(Ordering::Relaxed);
+        unsafe { ptr.as_ref().expect("ApiBackend::set not called") }
+    }
+}
+
+impl Default for ApiBackend {
+    fn default() -> ApiBackend {
+        ApiBackend::new()
+    }
+}
+
+impl Backend for ApiBackend {
+    fn open(&self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        (self.api().open)(FfiString::new(path), flags).into()
+    }
+
+    fn close(&self, fd: file::Handle) -> Result<()> {
+        (self.api().close)(fd).into()
+    }
+
+    fn write(&self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        (self.api().write)(fd, FfiByteSlice::new(buffer)).into()
+    }
+
+    fn read(&self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        (self.api().read)(fd, FfiBuffer::new(buffer)).into()
+    }
+
+    fn seek_set(&self, fd: file::Handle, position: u64) -> Result<()> {
+        (self.api().seek_set)(fd, position).into()
+    }
+
+    fn seek_cur(&self, fd: file::Handle, offset: i64) -> Result<u64> {
+        (self.api().seek_cur)(fd, offset).into()
+    }
+
+    fn seek_end(&self, fd: file::Handle) -> Result<u64> {
+        (self.api().seek_end)(fd).into()
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        (self.api().rename)(FfiString::new(old_path), FfiString::new(new_path)).into()
+    }
+
+    fn ioctl(&self, fd: file::Handle, command: u64, value: u64) -> Result<u64> {
+        (self.api().ioctl)(fd, command, value).into()
+    }
+
+    fn opendir(&self, path: &str) -> Result<dir::Handle> {
+        (self.api().opendir)(FfiString::new(path)).into()
+    }
+
+    fn closedir(&self, dir: dir::Handle) -> Result<()> {
+        (self.api().closedir)(dir).into()
+    }
+
+    fn readdir(&self, dir: dir::Handle) -> Result<dir::Entry> {
+        (self.api().readdir)(dir).into()
+    }
+
+    fn stat(&self, path: &str) -> Result<file::Stat> {
+        (self.api().stat)(FfiString::new(path)).into()
+    }
+
+    fn fstat(&self, fd: file::Handle) -> Result<file::Stat> {
+        (self.api().fstat)(fd).into()
+    }
+
+    fn deletefile(&self, path: &str) -> Result<()> {
+        (self.api().deletefile)(FfiString::new(path)).into()
+    }
+
+    fn deletedir(&self, path: &str) -> Result<()> {
+        (self.api().deletedir)(FfiString::new(path)).into()
+    }
+
+    fn chdir(&self, path: &str) -> Result<()> {
+        (self.api().chdir)(FfiString::new(path)).into()
+    }
+
+    fn dchdir(&self, dir: dir::Handle) -> Result<()> {
+        (self.api().dchdir)(dir).into()
+    }
+
+    fn pwd(&self, buffer: &mut [u8]) -> Result<usize> {
+        (self.api().pwd)(FfiBuffer::new(buffer)).into()
+    }
+
+    fn malloc(&self, size: usize, alignment: usize) -> Result<*mut core::ffi::c_void> {
+        (self.api().malloc)(size, alignment).into()
+    }
+
+    fn free(&self, ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        (self.api().free)(ptr, size, alignment)
+    }
+}
+
+/// The API and backend that were in use before a call to [`install`].
+///
+/// Give it to [`restore`] to put them back.
+pub struct Installed {
+    api: *mut Api,
+    backend: *mut (),
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Make the application use the given backend for all OS calls.
+///
+/// Returns what was in use before, so it can be put back with [`restore`].
+/// You don't need to call this if you use
+/// [`init_with_backend`](crate::init_with_backend).
+///
+/// # Safety
+///
+/// The table of functions and the backend it calls are stored separately,
+/// and the table casts the stored backend back to a `B`. If an OS call
+/// happens in between the two stores, the old table could be handed the new
+/// backend (or the other way around), and treat it as the wrong type. So
+/// nothing else may make an OS call, or call [`install`] or [`restore`],
+/// while this runs - not another thread, not an interrupt, and not the
+/// backend being replaced (so don't call this from inside a [`Backend`]
+/// method).
+pub unsafe fn install<B: Backend>(backend: &'static B) -> Installed {
+    let api: &'static Api = &Adapter::<B>::API;
+    let old_backend = BACKEND.load(Ordering::Relaxed);
+    BACKEND.store(backend as *const B as *mut (), Ordering::Relaxed);
+    let old_api = crate::API.load(Ordering::Relaxed);
+    crate::API.store(api as *const Api as *mut Api, Ordering::Relaxed);
+    Installed {
+        api: old_api,
+        backend: old_backend,
+    }
+}
+
+/// Put back whatever was in use before a call to [`install`].
+///
+/// # Safety
+///
+/// The same rules as for [`install`] apply - nothing else may make an OS
+/// call, or call [`install`] or [`restore`], while this runs.
+pub unsafe fn restore(previous: Installed) {
+    crate::API.store(previous.api, Ordering::Relaxed);
+    BACKEND.store(previous.backend, Ordering::Relaxed);
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,485 @@
+//! Fault injection
+//!
+//! Wraps a [`Backend`] in a [`FaultInjector`], which makes some of the calls
+//! fail according to a set of [`Faults`]. Use it to check that your
+//! application copes with errors, short reads, and the like, which the
+//! built-in fake OS never produces.
+//!
+//! The [`testing`](crate::testing) module can inject faults for you - see
+//! [`TestOs::faults`](crate::testing::TestOs::faults).
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use std::sync::Mutex;
+
+use super::{dir, file, Backend};
+use crate::{random::Rng, Error, Result};
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// Identifies one of the calls in the [`Api`](crate::Api).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum Syscall {
+    Open,
+    Close,
+    Write,
+    Read,
+    SeekSet,
+    SeekCur,
+    SeekEnd,
+    Rename,
+    Ioctl,
+    Opendir,
+    Closedir,
+    Readdir,
+    Stat,
+    Fstat,
+    Deletefile,
+    Deletedir,
+    Chdir,
+    Dchdir,
+    Pwd,
+    Malloc,
+    Free,
+}
+
+impl Syscall {
+    /// How many different calls there are.
+    pub const COUNT: usize = 21;
+
+    /// Get the name of the call, as used in the [`Api`](crate::Api).
+    pub fn name(self) -> &'static str {
+        match self {
+            Syscall::Open => "open",
+            Syscall::Close => "close",
+            Syscall::Write => "write",
+            Syscall::Read => "read",
+            Syscall::SeekSet => "seek_set",
+            Syscall::SeekCur => "seek_cur",
+            Syscall::SeekEnd => "seek_end",
+            Syscall::Rename => "rename",
+            Syscall::Ioctl => "ioctl",
+            Syscall::Opendir => "opendir",
+            Syscall::Closedir => "closedir",
+            Syscall::Readdir => "readdir",
+            Syscall::Stat => "stat",
+            Syscall::Fstat => "fstat",
+            Syscall::Deletefile => "deletefile",
+            Syscall::Deletedir => "deletedir",
+            Syscall::Chdir => "chdir",
+            Syscall::Dchdir => "dchdir",
+            Syscall::Pwd => "pwd",
+            Syscall::Malloc => "malloc",
+            Syscall::Free => "free",
+        }
+    }
+}
+
+impl core::fmt::Display for Syscall {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A set of faults to inject.
+///
+/// Starts off empty. Add faults with the builder methods.
+///
+/// ```
+/// use neotron_sdk::backend::fault::{Faults, Syscall};
+/// use neotron_sdk::Error;
+///
+/// // The third read fails, every read returns at most 4 bytes, and about one
+/// // call in fifty fails with a device error.
+/// let faults = Faults::new()
+///     .fail_nth(Syscall::Read, 3, Error::DeviceSpecific)
+///     .short_reads(4)
+///     .fail_randomly(1234, 50, Error::DeviceSpecific);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Faults {
+    rules: Vec<Rule>,
+    short_read: Option<usize>,
+    short_write: Option<usize>,
+    random: Option<RandomFault>,
+}
+
+impl Faults {
+    /// Create an empty set of faults.
+    pub const fn new() -> Faults {
+        Faults {
+            rules: Vec::new(),
+            short_read: None,
+            short_write: None,
+            random: None,
+        }
+    }
+
+    /// Make the `n`th call to `syscall` fail with the given error.
+    ///
+    /// Calls are counted from one. You can add as many of these as you like.
+    pub fn fail_nth(mut self, syscall: Syscall, n: usize, error: Error) -> Faults {
+        self.rules.push(Rule { syscall, n, error });
+        self
+    }
+
+    /// Make every `read` return at most `max` bytes.
+    pub fn short_reads(mut self, max: usize) -> Faults {
+        self.short_read = Some(max);
+        self
+    }
+
+    /// Make every `write` write at most `max` bytes.
+    ///
+    /// If there were more bytes than that, the rest are dropped and the write
+    /// fails with [`Error::DeviceSpecific`], like a disk that has filled up.
+    pub fn short_writes(mut self, max: usize) -> Faults {
+        self.short_write = Some(max);
+        self
+    }
+
+    /// Make any call fail, with a probability of 1 in `one_in`.
+    ///
+    /// The failures are chosen with a pseudo-random number generator, so the
+    /// same `seed` gives the same failures each time (as long as the
+    /// application makes the same calls).
+    pub fn fail_randomly(mut self, seed: u64, one_in: u32, error: Error) -> Faults {
+        self.random = Some(RandomFault {
+            rng: Rng::new(seed),
+            one_in: one_in.max(1),
+            error,
+        });
+        self
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Faults {
+        Faults::new()
+    }
+}
+
+/// A [`Backend`] which passes calls on to another backend, except when it
+/// injects a fault.
+///
+/// ```no_run
+/// use neotron_sdk::backend::{fault::{FaultInjector, Faults, Syscall}, Backend};
+///
+/// struct MyOs;
+///
+/// impl Backend for MyOs {}
+///
+/// static OS: FaultInjector<MyOs> = FaultInjector::new(MyOs);
+///
+/// OS.set_faults(Faults::new().fail_nth(Syscall::Open, 1, neotron_sdk::Error::NotFound));
+/// neotron_sdk::init_with_backend(&OS);
+/// ```
+pub struct FaultInjector<B> {
+    inner: B,
+    state: Mutex<State>,
+}
+
+impl<B: Backend> FaultInjector<B> {
+    /// Wrap a backend, with no faults.
+    pub const fn new(inner: B) -> FaultInjector<B> {
+        FaultInjector {
+            inner,
+            state: Mutex::new(State {
+                faults: Faults::new(),
+                calls: [0; Syscall::COUNT],
+            }),
+        }
+    }
+
+    /// Replace the faults to inject.
+    ///
+    /// This also resets all the call counts to zero.
+    pub fn set_faults(&self, faults: Faults) {
+        let mut state = self.state();
+        state.faults = faults;
+        state.calls = [0; Syscall::COUNT];
+    }
+
+    /// How many times has `syscall` been called (including the calls that
+    /// failed)?
+    pub fn calls(&self, syscall: Syscall) -> usize {
+        self.state().calls[syscall as usize]
+    }
+
+    /// Get the wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Lock the state, even if a previous holder panicked.
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count a call, and decide whether it should fail.
+    fn check(&self, syscall: Syscall) -> Result<()> {
+        let mut state = self.state();
+        state.calls[syscall as usize] += 1;
+        let n = state.calls[syscall as usize];
+        if let Some(rule) = state
+            .faults
+            .rules
+            .iter()
+            .find(|rule| rule.syscall == syscall && rule.n == n)
+        {
+            return Err(rule.error);
+        }
+        if let Some(random) = state.faults.random.as_mut() {
+            if random.rng.gen_range(0..random.one_in) == 0 {
+                return Err(random.error);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: Backend> Backend for FaultInjector<B> {
+    fn open(&self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        self.check(Syscall::Open)?;
+        self.inner.open(path, flags)
+    }
+
+    fn close(&self, fd: file::Handle) -> Result<()> {
+        self.check(Syscall::Close)?;
+        self.inner.close(fd)
+    }
+
+    fn write(&self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        self.check(Syscall::Write)?;
+        match self.state().faults.short_write {
+            Some(max) if buffer.len() > max => {
+                self.inner.write(fd, &buffer[0..max])?;
+                Err(Error::DeviceSpecific)
+            }
+            _ => self.inner.write(fd, buffer),
+        }
+    }
+
+    fn read(&self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        self.check(Syscall::Read)?;
+        let max = self.state().faults.short_read.unwrap_or(usize::MAX);
+        let len = buffer.len().min(max);
+        self.inner.read(fd, &mut buffer[0..len])
+    }
+
+    fn seek_set(&self, fd: file::Handle, position: u64) -> Result<()> {
+        self.check(Syscall::SeekSet)?;
+        self.inner.seek_set(fd, position)
+    }
+
+    fn seek_cur(&self, fd: file::Handle, offset: i64) -> Result<u64> {
+        self.check(Syscall::SeekCur)?;
+        self.inner.seek_cur(fd, offset)
+    }
+
+    fn seek_end(&self, fd: file::Handle) -> Result<u64> {
+        self.check(Syscall::SeekEnd)?;
+        self.inner.seek_end(fd)
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        self.check(Syscall::Rename)?;
+        self.inner.rename(old_path, new_path)
+    }
+
+    fn ioctl(&self, fd: file::Handle, command: u64, value: u64) -> Result<u64> {
+        self.check(Syscall::Ioctl)?;
+        self.inner.ioctl(fd, command, value)
+    }
+
+    fn opendir(&self, path: &str) -> Result<dir::Handle> {
+        self.check(Syscall::Opendir)?;
+        self.inner.opendir(path)
+    }
+
+    fn closedir(&self, dir: dir::Handle) -> Result<()> {
+        self.check(Syscall::Closedir)?;
+        self.inner.closedir(dir)
+    }
+
+    fn readdir(&self, dir: dir::Handle) -> Result<dir::Entry> {
+        self.check(Syscall::Readdir)?;
+        self.inner.readdir(dir)
+    }
+
+    fn stat(&self, path: &str) -> Result<file::Stat> {
+        self.check(Syscall::Stat)?;
+        self.inner.stat(path)
+    }
+
+    fn fstat(&self, fd: file::Handle) -> Result<file::Stat> {
+        self.check(Syscall::Fstat)?;
+        self.inner.fstat(fd)
+    }
+
+    fn deletefile(&self, path: &str) -> Result<()> {
+        self.check(Syscall::Deletefile)?;
+        self.inner.deletefile(path)
+    }
+
+    fn deletedir(&self, path: &str) -> Result<()> {
+        self.check(Syscall::Deletedir)?;
+        self.inner.deletedir(path)
+    }
+
+    fn chdir(&self, path: &str) -> Result<()> {
+        self.check(Syscall::Chdir)?;
+        self.inner.chdir(path)
+    }
+
+    fn dchdir(&self, dir: dir::Handle) -> Result<()> {
+        self.check(Syscall::Dchdir)?;
+        self.inner.dchdir(dir)
+    }
+
+    fn pwd(&self, buffer: &mut [u8]) -> Result<usize> {
+        self.check(Syscall::Pwd)?;
+        self.inner.pwd(buffer)
+    }
+
+    fn malloc(&self, size: usize, alignment: usize) -> Result<*mut core::ffi::c_void> {
+        self.check(Syscall::Malloc)?;
+        self.inner.malloc(size, alignment)
+    }
+
+    fn free(&self, ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        // We can't fail this one, because it doesn't return anything. Freeing
+        // memory should always work anyway.
+        let _ = self.check(Syscall::Free);
+        self.inner.free(ptr, size, alignment)
+    }
+}
+
+/// Fail the `n`th call to a given syscall.
+#[derive(Debug, Clone)]
+struct Rule {
+    syscall: Syscall,
+    n: usize,
+    error: Error,
+}
+
+/// Fail calls at random.
+#[derive(Debug, Clone)]
+struct RandomFault {
+    rng: Rng,
+    one_in: u32,
+    error: Error,
+}
+
+/// The mutable part of a [`FaultInjector`].
+struct State {
+    faults: Faults,
+    calls: [usize; Syscall::COUNT],
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A backend which keeps everything written, and reads `x`s forever.
+    #[derive(Default)]
+    struct Sink {
+        written: Mutex<Vec<u8>>,
+    }
+
+    impl Backend for Sink {
+        fn write(&self, _fd: file::Handle, buffer: &[u8]) -> Result<()> {
+            self.written.lock().unwrap().extend_from_slice(buffer);
+            Ok(())
+        }
+
+        fn read(&self, _fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+            buffer.fill(b'x');
+            Ok(buffer.len())
+        }
+    }
+
+    fn injector(faults: Faults) -> FaultInjector<Sink> {
+        let injector = FaultInjector::new(Sink::default());
+        injector.set_faults(faults);
+        injector
+    }
+
+    #[test]
+    fn fail_nth_only_fails_that_call() {
+        let os = injector(Faults::new().fail_nth(Syscall::Write, 2, Error::DeviceSpecific));
+        let fd = file::Handle::new_stdout();
+        assert_eq!(os.write(fd, b"a"), Ok(()));
+        assert_eq!(os.write(fd, b"b"), Err(Error::DeviceSpecific));
+        assert_eq!(os.write(fd, b"c"), Ok(()));
+        assert_eq!(os.read(fd, &mut [0u8; 2]), Ok(2));
+        assert_eq!(*os.inner().written.lock().unwrap(), b"ac");
+        assert_eq!(os.calls(Syscall::Write), 3);
+        assert_eq!(os.calls(Syscall::Read), 1);
+    }
+
+    #[test]
+    fn set_faults_resets_the_counts() {
+        let os = injector(Faults::new().fail_nth(Syscall::Write, 1, Error::DeviceSpecific));
+        let fd = file::Handle::new_stdout();
+        assert!(os.write(fd, b"a").is_err());
+        os.set_faults(Faults::new().fail_nth(Syscall::Write, 1, Error::DeviceSpecific));
+        assert_eq!(os.calls(Syscall::Write), 0);
+        assert!(os.write(fd, b"a").is_err());
+        assert!(os.write(fd, b"a").is_ok());
+    }
+
+    #[test]
+    fn short_reads() {
+        let os = injector(Faults::new().short_reads(3));
+        let mut buffer = [0u8; 8];
+        assert_eq!(os.read(file::Handle::new_stdin(), &mut buffer), Ok(3));
+        assert_eq!(&buffer, b"xxx\0\0\0\0\0");
+        assert_eq!(os.read(file::Handle::new_stdin(), &mut buffer[0..2]), Ok(2));
+    }
+
+    #[test]
+    fn short_writes_drop_the_rest() {
+        let os = injector(Faults::new().short_writes(3));
+        let fd = file::Handle::new_stdout();
+        assert_eq!(os.write(fd, b"abc"), Ok(()));
+        assert_eq!(os.write(fd, b"defgh"), Err(Error::DeviceSpecific));
+        assert_eq!(*os.inner().written.lock().unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn random_faults_repeat_with_the_same_seed() {
+        let run = |seed| {
+            let os = injector(Faults::new().fail_randomly(seed, 3, Error::DeviceSpecific));
+            (0..64)
+                .map(|_| os.write(file::Handle::new_stdout(), b"a").is_err())
+                .collect::<Vec<bool>>()
+        };
+        let first = run(1234);
+        assert_eq!(first, run(1234));
+        assert!(first.iter().any(|failed| *failed));
+        assert!(first.iter().any(|failed| !*failed));
+    }
+
+    #[test]
+    fn random_faults_one_in_one_always_fail() {
+        let os = injector(Faults::new().fail_randomly(1, 1, Error::NotFound));
+        for _ in 0..8 {
+            assert_eq!(os.stat("HD0:/A"), Err(Error::NotFound));
+        }
+        assert_eq!(os.calls(Syscall::Stat), 8);
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
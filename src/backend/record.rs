@@ -0,0 +1,1034 @@
+//! Recording and replaying syscalls
+//!
+//! Wrap a [`Backend`] in a [`Recorder`] and every call the application makes,
+//! along with what the OS gave back, is written to a log file. One line per
+//! call, in plain text:
+//!
+//! ```text
+//! # neotron-sdk syscall log v1
+//! open("HD0:/README.TXT", 0x00) = ok 3
+//! read(3, 16) = ok 48656c6c6f2c20776f726c64210a
+//! read(3, 16) = err EndOfFile
+//! close(3) = ok
+//! read(0, 16) = ok 71
+//! ```
+//!
+//! Bytes the OS handed over (file contents, keypresses, the current
+//! directory) are written in hexadecimal. The bytes the application wrote are
+//! not recorded, only how many there were.
+//!
+//! On UNIX and Windows, a `Replayer` reads a log back and acts as the OS,
+//! giving the application exactly the same results in exactly the same order.
+//! If the application makes a call which doesn't match the log, the replay
+//! has *diverged* - the replayer says so on the host's stderr, and fails that
+//! call (and every call after it) with
+//! [`DeviceSpecific`](crate::Error::DeviceSpecific).
+//!
+//! So, to reproduce a bug seen on real hardware:
+//!
+//! 1. Build your application with the `record` feature, and run it on Neotron
+//!    OS. The log goes to the file named in the `NEOTRON_RECORD_FILE`
+//!    environment variable at build time, or `RECORD.LOG` in the current
+//!    directory if that isn't set.
+//! 2. Copy the log to your PC.
+//! 3. Build your application for your PC, and run it with the
+//!    `NEOTRON_REPLAY` environment variable set to the log's path.
+//!
+//! You can also record on your PC - run the application with the
+//! `NEOTRON_RECORD` environment variable set to the path of the log to write.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use core::fmt::{Display, Write};
+
+use super::{dir, file, Backend};
+use crate::Result;
+
+#[cfg(not(target_os = "none"))]
+use crate::Error;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The first line of every log.
+const HEADER: &str = "# neotron-sdk syscall log v1";
+
+/// How much of a log line we collect before writing it out.
+const CHUNK_LEN: usize = 128;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A [`Backend`] which passes calls on to another backend, and records them
+/// in a log.
+///
+/// Nothing is recorded until you call [`Recorder::start`].
+pub struct Recorder<B> {
+    inner: B,
+    #[cfg(target_os = "none")]
+    log: core::sync::atomic::AtomicUsize,
+    #[cfg(not(target_os = "none"))]
+    log: std::sync::Mutex<Option<std::fs::File>>,
+}
+
+impl<B: Backend> Recorder<B> {
+    /// Means we have no log file open.
+    #[cfg(target_os = "none")]
+    const NOT_RECORDING: usize = usize::MAX;
+
+    /// Wrap a backend.
+    pub const fn new(inner: B) -> Recorder<B> {
+        Recorder {
+            inner,
+            #[cfg(target_os = "none")]
+            log: core::sync::atomic::AtomicUsize::new(Self::NOT_RECORDING),
+            #[cfg(not(target_os = "none"))]
+            log: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Get the wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Start recording, to a new file at `path`.
+    ///
+    /// The file is opened using the wrapped backend, and the calls we make to
+    /// write to it are not recorded.
+    #[cfg(target_os = "none")]
+    pub fn start(&self, path: &str) -> Result<()> {
+        use core::sync::atomic::Ordering;
+        self.stop();
+        let flags = file::Flags::WRITE | file::Flags::CREATE | file::Flags::TRUNCATE;
+        let fd = self.inner.open(path, flags)?;
+        self.log.store(fd.value() as usize, Ordering::Relaxed);
+        self.emit(|w| writeln!(w, "{}", HEADER));
+        Ok(())
+    }
+
+    /// Start recording, to a new file at `path` on the host.
+    #[cfg(not(target_os = "none"))]
+    pub fn start<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        *self.log.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+        self.emit(|w| writeln!(w, "{}", HEADER));
+        Ok(())
+    }
+
+    /// Stop recording, and close the log file.
+    #[cfg(target_os = "none")]
+    pub fn stop(&self) {
+        use core::sync::atomic::Ordering;
+        let fd = self.log.load(Ordering::Relaxed);
+        if fd != Self::NOT_RECORDING {
+            self.log.store(Self::NOT_RECORDING, Ordering::Relaxed);
+            let _ = self.inner.close(file::Handle::new(fd as u8));
+        }
+    }
+
+    /// Stop recording, and close the log file.
+    #[cfg(not(target_os = "none"))]
+    pub fn stop(&self) {
+        *self.log.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Record a call that has finished.
+    fn record<T: Display>(&self, call: Call, result: Result<T>) {
+        self.emit(|w| match result {
+            Ok(value) => writeln!(w, "{} = ok{}", call, value),
+            Err(e) => writeln!(w, "{} = err {:?}", call, e),
+        });
+    }
+
+    /// Write a line to the log file, if we're recording.
+    #[cfg(target_os = "none")]
+    fn emit<F: FnOnce(&mut Chunker<'_>) -> core::fmt::Result>(&self, f: F) {
+        use core::sync::atomic::Ordering;
+        let fd = self.log.load(Ordering::Relaxed);
+        if fd == Self::NOT_RECORDING {
+            return;
+        }
+        let fd = file::Handle::new(fd as u8);
+        let mut sink = |data: &[u8]| {
+            let _ = self.inner.write(fd, data);
+        };
+        let mut chunker = Chunker::new(&mut sink);
+        let _ = f(&mut chunker);
+        chunker.flush();
+    }
+
+    /// Write a line to the log file, if we're recording.
+    #[cfg(not(target_os = "none"))]
+    fn emit<F: FnOnce(&mut Chunker<'_>) -> core::fmt::Result>(&self, f: F) {
+        use std::io::Write;
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(file) = log.as_mut() else {
+            return;
+        };
+        let mut sink = |data: &[u8]| {
+            let _ = file.write_all(data);
+        };
+        let mut chunker = Chunker::new(&mut sink);
+        let _ = f(&mut chunker);
+        chunker.flush();
+    }
+}
+
+impl<B: Backend> Backend for Recorder<B> {
+    fn open(&self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        let result = self.inner.open(path, flags);
+        let value = result.map(|fd| Number(fd.value().into()));
+        self.record(Call::Open(path, flags), value);
+        result
+    }
+
+    fn close(&self, fd: file::Handle) -> Result<()> {
+        let result = self.inner.close(fd);
+        self.record(Call::Close(fd), result.map(|_| Nothing));
+        result
+    }
+
+    fn write(&self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        let result = self.inner.write(fd, buffer);
+        self.record(Call::Write(fd, buffer.len()), result.map(|_| Nothing));
+        result
+    }
+
+    fn read(&self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        let len = buffer.len();
+        let result = self.inner.read(fd, buffer);
+        let value = match result {
+            Ok(n) => Ok(Hex(&buffer[0..n.min(len)])),
+            Err(e) => Err(e),
+        };
+        self.record(Call::Read(fd, len), value);
+        result
+    }
+
+    fn seek_set(&self, fd: file::Handle, position: u64) -> Result<()> {
+        let result = self.inner.seek_set(fd, position);
+        self.record(Call::SeekSet(fd, position), result.map(|_| Nothing));
+        result
+    }
+
+    fn seek_cur(&self, fd: file::Handle, offset: i64) -> Result<u64> {
+        let result = self.inner.seek_cur(fd, offset);
+        self.record(Call::SeekCur(fd, offset), result.map(Number));
+        result
+    }
+
+    fn seek_end(&self, fd: file::Handle) -> Result<u64> {
+        let result = self.inner.seek_end(fd);
+        self.record(Call::SeekEnd(fd), result.map(Number));
+        result
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        let result = self.inner.rename(old_path, new_path);
+        self.record(Call::Rename(old_path, new_path), result.map(|_| Nothing));
+        result
+    }
+
+    fn ioctl(&self, fd: file::Handle, command: u64, value: u64) -> Result<u64> {
+        let result = self.inner.ioctl(fd, command, value);
+        self.record(Call::Ioctl(fd, command, value), result.map(Number));
+        result
+    }
+
+    fn opendir(&self, path: &str) -> Result<dir::Handle> {
+        let result = self.inner.opendir(path);
+        let value = result.map(|dir| Number(dir.value().into()));
+        self.record(Call::Opendir(path), value);
+        result
+    }
+
+    fn closedir(&self, dir: dir::Handle) -> Result<()> {
+        let result = self.inner.closedir(dir);
+        self.record(Call::Closedir(dir), result.map(|_| Nothing));
+        result
+    }
+
+    fn readdir(&self, dir: dir::Handle) -> Result<dir::Entry> {
+        let result = self.inner.readdir(dir);
+        self.record(
+            Call::Readdir(dir),
+            result.as_ref().map(EntryRecord).map_err(|e| *e),
+        );
+        result
+    }
+
+    fn stat(&self, path: &str) -> Result<file::Stat> {
+        let result = self.inner.stat(path);
+        self.record(
+            Call::Stat(path),
+            result.as_ref().map(StatRecord).map_err(|e| *e),
+        );
+        result
+    }
+
+    fn fstat(&self, fd: file::Handle) -> Result<file::Stat> {
+        let result = self.inner.fstat(fd);
+        self.record(
+            Call::Fstat(fd),
+            result.as_ref().map(StatRecord).map_err(|e| *e),
+        );
+        result
+    }
+
+    fn deletefile(&self, path: &str) -> Result<()> {
+        let result = self.inner.deletefile(path);
+        self.record(Call::Deletefile(path), result.map(|_| Nothing));
+        result
+    }
+
+    fn deletedir(&self, path: &str) -> Result<()> {
+        let result = self.inner.deletedir(path);
+        self.record(Call::Deletedir(path), result.map(|_| Nothing));
+        result
+    }
+
+    fn chdir(&self, path: &str) -> Result<()> {
+        let result = self.inner.chdir(path);
+        self.record(Call::Chdir(path), result.map(|_| Nothing));
+        result
+    }
+
+    fn dchdir(&self, dir: dir::Handle) -> Result<()> {
+        let result = self.inner.dchdir(dir);
+        self.record(Call::Dchdir(dir), result.map(|_| Nothing));
+        result
+    }
+
+    fn pwd(&self, buffer: &mut [u8]) -> Result<usize> {
+        let len = buffer.len();
+        let result = self.inner.pwd(buffer);
+        let value = match result {
+            Ok(n) => Ok(Hex(&buffer[0..n.min(len)])),
+            Err(e) => Err(e),
+        };
+        self.record(Call::Pwd(len), value);
+        result
+    }
+
+    fn malloc(&self, size: usize, alignment: usize) -> Result<*mut core::ffi::c_void> {
+        // Addresses won't be the same next time, so we only record whether it
+        // worked.
+        let result = self.inner.malloc(size, alignment);
+        self.record(Call::Malloc(size, alignment), result.map(|_| Nothing));
+        result
+    }
+
+    fn free(&self, ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        self.inner.free(ptr, size, alignment);
+        self.record(Call::Free(size, alignment), Ok(Nothing));
+    }
+}
+
+/// A [`Backend`] which plays back a log made by a [`Recorder`].
+///
+/// Writes to Standard Output and Standard Error are passed on to the host, so
+/// you can see what the application printed. Memory from `malloc` comes from
+/// the host's allocator.
+///
+/// ```
+/// use neotron_sdk::backend::{file, record::Replayer, Backend};
+///
+/// let os = Replayer::from_log("read(0, 16) = ok 7171\nread(0, 16) = err EndOfFile\n").unwrap();
+/// let mut buffer = [0u8; 16];
+/// assert_eq!(os.read(file::Handle::new_stdin(), &mut buffer), Ok(2));
+/// assert_eq!(&buffer[0..2], b"qq");
+/// assert_eq!(os.read(file::Handle::new_stdin(), &mut buffer), Err(neotron_sdk::Error::EndOfFile));
+/// assert!(os.divergence().is_none());
+/// assert_eq!(os.remaining(), 0);
+///
+/// // A call the log doesn't have
+/// assert!(os.close(file::Handle::new(3)).is_err());
+/// assert!(os.divergence().is_some());
+/// ```
+#[cfg(not(target_os = "none"))]
+pub struct Replayer {
+    state: std::sync::Mutex<ReplayState>,
+}
+
+/// Where we've got to in the log.
+#[cfg(not(target_os = "none"))]
+struct ReplayState {
+    records: Vec<ReplayRecord>,
+    next: usize,
+    divergence: Option<String>,
+}
+
+/// One line from the log.
+#[cfg(not(target_os = "none"))]
+struct ReplayRecord {
+    /// Which line of the log this was
+    line_no: usize,
+    /// The call, as formatted by [`Call`]
+    call: String,
+    /// Everything after the ` = `
+    result: String,
+}
+
+#[cfg(not(target_os = "none"))]
+impl Replayer {
+    /// Load a log from a file on the host.
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Replayer> {
+        let text = std::fs::read_to_string(path)?;
+        Replayer::from_log(&text)
+    }
+
+    /// Load a log from a string.
+    ///
+    /// Fails if any line doesn't look like a log record.
+    pub fn from_log(text: &str) -> std::io::Result<Replayer> {
+        let mut records = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((call, result)) = line.rsplit_once(" = ") else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: not a log record: {:?}", idx + 1, line),
+                ));
+            };
+            records.push(ReplayRecord {
+                line_no: idx + 1,
+                call: call.to_owned(),
+                result: result.to_owned(),
+            });
+        }
+        Ok(Replayer {
+            state: std::sync::Mutex::new(ReplayState {
+                records,
+                next: 0,
+                divergence: None,
+            }),
+        })
+    }
+
+    /// Describes where the application first stopped following the log, if
+    /// it has.
+    pub fn divergence(&self) -> Option<String> {
+        self.lock().divergence.clone()
+    }
+
+    /// How many calls in the log haven't been made yet.
+    pub fn remaining(&self) -> usize {
+        let state = self.lock();
+        state.records.len() - state.next
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check `call` against the next record, and give back the recorded
+    /// result.
+    ///
+    /// The `parse` function converts the words after `ok` into a value. If
+    /// the call is wrong, or the result can't be parsed, the replay has
+    /// diverged.
+    fn replay<T, F>(&self, call: Call, parse: F) -> Result<T>
+    where
+        F: FnOnce(&mut core::str::SplitWhitespace) -> Option<T>,
+    {
+        let mut state = self.lock();
+        if state.divergence.is_some() {
+            return Err(Error::DeviceSpecific);
+        }
+        let actual = call.to_string();
+        let Some(record) = state.records.get(state.next) else {
+            let message = format!("got `{}` after the end of the log", actual);
+            return Err(diverge(&mut state, message));
+        };
+        if record.call != actual {
+            let message = format!(
+                "expected `{}` (line {}), got `{}`",
+                record.call, record.line_no, actual
+            );
+            return Err(diverge(&mut state, message));
+        }
+        let mut words = record.result.split_whitespace();
+        let value = match words.next() {
+            Some("ok") => parse(&mut words).map(Ok),
+            Some("err") => words.next().and_then(parse_error).map(Err),
+            _ => None,
+        };
+        match value {
+            Some(value) if words.next().is_none() => {
+                state.next += 1;
+                value
+            }
+            _ => {
+                let message = format!(
+                    "cannot understand the result `{}` on line {}",
+                    record.result, record.line_no
+                );
+                Err(diverge(&mut state, message))
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+impl Backend for Replayer {
+    fn open(&self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        self.replay(Call::Open(path, flags), |words| {
+            parse_number(words).map(file::Handle::new)
+        })
+    }
+
+    fn close(&self, fd: file::Handle) -> Result<()> {
+        self.replay(Call::Close(fd), |_| Some(()))
+    }
+
+    fn write(&self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        self.replay(Call::Write(fd, buffer.len()), |_| Some(()))?;
+        if fd == file::Handle::new_stdout() || fd == file::Handle::new_stderr() {
+            let _ = crate::fake_os_api::HostOs.write(fd, buffer);
+        }
+        Ok(())
+    }
+
+    fn read(&self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        let len = buffer.len();
+        let data = self.replay(Call::Read(fd, len), |words| {
+            parse_hex(words).filter(|data| data.len() <= len)
+        })?;
+        buffer[0..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn seek_set(&self, fd: file::Handle, position: u64) -> Result<()> {
+        self.replay(Call::SeekSet(fd, position), |_| Some(()))
+    }
+
+    fn seek_cur(&self, fd: file::Handle, offset: i64) -> Result<u64> {
+        self.replay(Call::SeekCur(fd, offset), parse_number)
+    }
+
+    fn seek_end(&self, fd: file::Handle) -> Result<u64> {
+        self.replay(Call::SeekEnd(fd), parse_number)
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        self.replay(Call::Rename(old_path, new_path), |_| Some(()))
+    }
+
+    fn ioctl(&self, fd: file::Handle, command: u64, value: u64) -> Result<u64> {
+        self.replay(Call::Ioctl(fd, command, value), parse_number)
+    }
+
+    fn opendir(&self, path: &str) -> Result<dir::Handle> {
+        self.replay(Call::Opendir(path), |words| {
+            parse_number(words).map(dir::Handle::new)
+        })
+    }
+
+    fn closedir(&self, dir: dir::Handle) -> Result<()> {
+        self.replay(Call::Closedir(dir), |_| Some(()))
+    }
+
+    fn readdir(&self, dir: dir::Handle) -> Result<dir::Entry> {
+        self.replay(Call::Readdir(dir), |words| {
+            let name = parse_hex(words)?.try_into().ok()?;
+            let properties = parse_stat(words)?;
+            Some(dir::Entry { name, properties })
+        })
+    }
+
+    fn stat(&self, path: &str) -> Result<file::Stat> {
+        self.replay(Call::Stat(path), parse_stat)
+    }
+
+    fn fstat(&self, fd: file::Handle) -> Result<file::Stat> {
+        self.replay(Call::Fstat(fd), parse_stat)
+    }
+
+    fn deletefile(&self, path: &str) -> Result<()> {
+        self.replay(Call::Deletefile(path), |_| Some(()))
+    }
+
+    fn deletedir(&self, path: &str) -> Result<()> {
+        self.replay(Call::Deletedir(path), |_| Some(()))
+    }
+
+    fn chdir(&self, path: &str) -> Result<()> {
+        self.replay(Call::Chdir(path), |_| Some(()))
+    }
+
+    fn dchdir(&self, dir: dir::Handle) -> Result<()> {
+        self.replay(Call::Dchdir(dir), |_| Some(()))
+    }
+
+    fn pwd(&self, buffer: &mut [u8]) -> Result<usize> {
+        let len = buffer.len();
+        let data = self.replay(Call::Pwd(len), |words| {
+            parse_hex(words).filter(|data| data.len() <= len)
+        })?;
+        buffer[0..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn malloc(&self, size: usize, alignment: usize) -> Result<*mut core::ffi::c_void> {
+        self.replay(Call::Malloc(size, alignment), |_| Some(()))?;
+        let layout = std::alloc::Layout::from_size_align(size.max(1), alignment)
+            .map_err(|_| Error::InvalidArg)?;
+        // Safety: the layout has a non-zero size
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        if ptr.is_null() {
+            Err(Error::OutOfMemory)
+        } else {
+            Ok(ptr as *mut core::ffi::c_void)
+        }
+    }
+
+    fn free(&self, ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        let _ = self.replay(Call::Free(size, alignment), |_| Some(()));
+        if ptr.is_null() {
+            return;
+        }
+        if let Ok(layout) = std::alloc::Layout::from_size_align(size.max(1), alignment) {
+            // Safety: the application must give back what `malloc` gave it
+            unsafe { std::alloc::dealloc(ptr as *mut u8, layout) };
+        }
+    }
+}
+
+/// A call, as written in the log.
+///
+/// The [`Recorder`] and the replayer both format calls with this, so they
+/// always agree on what a call looks like.
+enum Call<'a> {
+    Open(&'a str, file::Flags),
+    Close(file::Handle),
+    Write(file::Handle, usize),
+    Read(file::Handle, usize),
+    SeekSet(file::Handle, u64),
+    SeekCur(file::Handle, i64),
+    SeekEnd(file::Handle),
+    Rename(&'a str, &'a str),
+    Ioctl(file::Handle, u64, u64),
+    Opendir(&'a str),
+    Closedir(dir::Handle),
+    Readdir(dir::Handle),
+    Stat(&'a str),
+    Fstat(file::Handle),
+    Deletefile(&'a str),
+    Deletedir(&'a str),
+    Chdir(&'a str),
+    Dchdir(dir::Handle),
+    Pwd(usize),
+    Malloc(usize, usize),
+    Free(usize, usize),
+}
+
+impl Display for Call<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Call::Open(path, flags) => write!(f, "open({:?}, {:#04x})", path, flags.bits()),
+            Call::Close(fd) => write!(f, "close({})", fd.value()),
+            Call::Write(fd, len) => write!(f, "write({}, {})", fd.value(), len),
+            Call::Read(fd, len) => write!(f, "read({}, {})", fd.value(), len),
+            Call::SeekSet(fd, position) => write!(f, "seek_set({}, {})", fd.value(), position),
+            Call::SeekCur(fd, offset) => write!(f, "seek_cur({}, {})", fd.value(), offset),
+            Call::SeekEnd(fd) => write!(f, "seek_end({})", fd.value()),
+            Call::Rename(old, new) => write!(f, "rename({:?}, {:?})", old, new),
+            Call::Ioctl(fd, command, value) => {
+                write!(f, "ioctl({}, {:#x}, {:#x})", fd.value(), command, value)
+            }
+            Call::Opendir(path) => write!(f, "opendir({:?})", path),
+            Call::Closedir(dir) => write!(f, "closedir({})", dir.value()),
+            Call::Readdir(dir) => write!(f, "readdir({})", dir.value()),
+            Call::Stat(path) => write!(f, "stat({:?})", path),
+            Call::Fstat(fd) => write!(f, "fstat({})", fd.value()),
+            Call::Deletefile(path) => write!(f, "deletefile({:?})", path),
+            Call::Deletedir(path) => write!(f, "deletedir({:?})", path),
+            Call::Chdir(path) => write!(f, "chdir({:?})", path),
+            Call::Dchdir(dir) => write!(f, "dchdir({})", dir.value()),
+            Call::Pwd(len) => write!(f, "pwd({})", len),
+            Call::Malloc(size, alignment) => write!(f, "malloc({}, {})", size, alignment),
+            Call::Free(size, alignment) => write!(f, "free({}, {})", size, alignment),
+        }
+    }
+}
+
+/// A successful result with no value.
+struct Nothing;
+
+impl Display for Nothing {
+    fn fmt(&self, _f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
+/// A successful result which is a number.
+struct Number(u64);
+
+impl Display for Number {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, " {}", self.0)
+    }
+}
+
+/// A successful result which is some bytes.
+struct Hex<'a>(&'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.0.is_empty() {
+            // So there's always a word to parse
+            return f.write_str(" -");
+        }
+        f.write_char(' ')?;
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// A successful result which is a [`file::Stat`].
+struct StatRecord<'a>(&'a file::Stat);
+
+impl Display for StatRecord<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, " {} {}", self.0.file_size, self.0.attr.bits())?;
+        for time in [&self.0.ctime, &self.0.mtime] {
+            write!(
+                f,
+                " {} {} {} {} {} {}",
+                time.year_since_1970,
+                time.zero_indexed_month,
+                time.zero_indexed_day,
+                time.hours,
+                time.minutes,
+                time.seconds
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A successful result which is a [`dir::Entry`].
+struct EntryRecord<'a>(&'a dir::Entry);
+
+impl Display for EntryRecord<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}{}", Hex(&self.0.name), StatRecord(&self.0.properties))
+    }
+}
+
+/// Collects formatted text, and passes it to a sink in chunks.
+///
+/// Saves us making one OS call for every little piece of a log line.
+struct Chunker<'a> {
+    sink: &'a mut dyn FnMut(&[u8]),
+    buffer: [u8; CHUNK_LEN],
+    len: usize,
+}
+
+impl<'a> Chunker<'a> {
+    fn new(sink: &'a mut dyn FnMut(&[u8])) -> Chunker<'a> {
+        Chunker {
+            sink,
+            buffer: [0u8; CHUNK_LEN],
+            len: 0,
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.len != 0 {
+            (self.sink)(&self.buffer[0..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl Write for Chunker<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            if self.len == CHUNK_LEN {
+                self.flush();
+            }
+            self.buffer[self.len] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Note that the replay has diverged from the log, and say so on the host's
+/// stderr.
+#[cfg(not(target_os = "none"))]
+fn diverge(state: &mut ReplayState, message: String) -> Error {
+    eprintln!("[replay] diverged at call {}: {}", state.next + 1, message);
+    state.divergence = Some(message);
+    Error::DeviceSpecific
+}
+
+/// Parse the name of an [`Error`], as written by its `Debug` impl.
+#[cfg(not(target_os = "none"))]
+fn parse_error(word: &str) -> Option<Error> {
+    let e = match word {
+        "NotFound" => Error::NotFound,
+        "FileReadOnly" => Error::FileReadOnly,
+        "EndOfFile" => Error::EndOfFile,
+        "Unimplemented" => Error::Unimplemented,
+        "InvalidArg" => Error::InvalidArg,
+        "BadHandle" => Error::BadHandle,
+        "DeviceSpecific" => Error::DeviceSpecific,
+        "OutOfMemory" => Error::OutOfMemory,
+        "InvalidPath" => Error::InvalidPath,
+        _ => return None,
+    };
+    Some(e)
+}
+
+/// Parse the next word as a number.
+#[cfg(not(target_os = "none"))]
+fn parse_number<T: core::str::FromStr>(words: &mut core::str::SplitWhitespace) -> Option<T> {
+    words.next()?.parse().ok()
+}
+
+/// Parse the next word as some bytes, written by [`Hex`].
+#[cfg(not(target_os = "none"))]
+fn parse_hex(words: &mut core::str::SplitWhitespace) -> Option<Vec<u8>> {
+    let word = words.next()?;
+    if word == "-" {
+        return Some(Vec::new());
+    }
+    if word.len() % 2 != 0 {
+        return None;
+    }
+    (0..word.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(word.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse the next few words as a [`file::Stat`], written by [`StatRecord`].
+#[cfg(not(target_os = "none"))]
+fn parse_stat(words: &mut core::str::SplitWhitespace) -> Option<file::Stat> {
+    let file_size = parse_number(words)?;
+    let attr = file::Attributes::from_bits_retain(parse_number(words)?);
+    let mut parse_time = || -> Option<file::Time> {
+        Some(file::Time {
+            year_since_1970: parse_number(words)?,
+            zero_indexed_month: parse_number(words)?,
+            zero_indexed_day: parse_number(words)?,
+            hours: parse_number(words)?,
+            minutes: parse_number(words)?,
+            seconds: parse_number(words)?,
+        })
+    };
+    let ctime = parse_time()?;
+    let mtime = parse_time()?;
+    Some(file::Stat {
+        file_size,
+        ctime,
+        mtime,
+        attr,
+    })
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A small disk with one file, `HD0:/HELLO.TXT`.
+    #[derive(Default)]
+    struct Disk {
+        /// How far through the file we have read.
+        offset: Mutex<usize>,
+    }
+
+    const CONTENTS: &[u8] = b"Hello, world";
+
+    fn stat() -> file::Stat {
+        let time = file::Time {
+            year_since_1970: 54,
+            zero_indexed_month: 4,
+            zero_indexed_day: 18,
+            hours: 14,
+            minutes: 30,
+            seconds: 0,
+        };
+        file::Stat {
+            file_size: CONTENTS.len() as u64,
+            ctime: time,
+            mtime: time,
+            attr: file::Attributes::ARCHIVE,
+        }
+    }
+
+    impl Backend for Disk {
+        fn open(&self, path: &str, _flags: file::Flags) -> Result<file::Handle> {
+            if path == "HD0:/HELLO.TXT" {
+                Ok(file::Handle::new(3))
+            } else {
+                Err(Error::NotFound)
+            }
+        }
+
+        fn close(&self, _fd: file::Handle) -> Result<()> {
+            Ok(())
+        }
+
+        fn write(&self, _fd: file::Handle, _buffer: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn read(&self, _fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+            let mut offset = self.offset.lock().unwrap();
+            let rest = &CONTENTS[*offset..];
+            if rest.is_empty() {
+                return Err(Error::EndOfFile);
+            }
+            let n = rest.len().min(buffer.len());
+            buffer[0..n].copy_from_slice(&rest[0..n]);
+            *offset += n;
+            Ok(n)
+        }
+
+        fn stat(&self, _path: &str) -> Result<file::Stat> {
+            Ok(stat())
+        }
+
+        fn opendir(&self, _path: &str) -> Result<dir::Handle> {
+            Ok(dir::Handle::new(1))
+        }
+
+        fn readdir(&self, _dir: dir::Handle) -> Result<dir::Entry> {
+            // The OS gives us "NAME.EXT", padded with NULs
+            let mut name = [0u8; 11];
+            name[0..9].copy_from_slice(b"HELLO.TXT");
+            Ok(dir::Entry {
+                name,
+                properties: stat(),
+            })
+        }
+
+        fn pwd(&self, buffer: &mut [u8]) -> Result<usize> {
+            buffer[0..5].copy_from_slice(b"HD0:/");
+            Ok(5)
+        }
+    }
+
+    /// Make some calls, and describe what came back.
+    fn session(os: &dyn Backend) -> Vec<String> {
+        let mut results = Vec::new();
+        results.push(format!(
+            "{:?}",
+            os.open("HD0:/MISSING.TXT", file::Flags::empty())
+        ));
+        results.push(format!(
+            "{:?}",
+            os.open("HD0:/HELLO.TXT", file::Flags::empty())
+        ));
+        loop {
+            let mut buffer = [0u8; 5];
+            let result = os.read(file::Handle::new(3), &mut buffer);
+            results.push(format!("{:?} {:?}", result, buffer));
+            if result.is_err() {
+                break;
+            }
+        }
+        results.push(format!("{:?}", os.close(file::Handle::new(3))));
+        results.push(format!("{:?}", os.write(file::Handle::new_stdout(), b"Hi")));
+        results.push(format!("{:?}", os.stat("HD0:/HELLO.TXT")));
+        results.push(format!("{:?}", os.opendir("HD0:/")));
+        results.push(format!("{:?}", os.readdir(dir::Handle::new(1))));
+        let mut buffer = [0u8; 16];
+        results.push(format!("{:?} {:?}", os.pwd(&mut buffer), buffer));
+        results.push(format!("{:?}", os.ioctl(file::Handle::new(3), 1, 2)));
+        results
+    }
+
+    /// Record a session with `os`, and give back the log.
+    fn record<B: Backend>(os: B, name: &str) -> (Vec<String>, String) {
+        let path =
+            std::env::temp_dir().join(format!("neotron-sdk-{}-{}.log", name, std::process::id()));
+        let recorder = Recorder::new(os);
+        recorder.start(&path).unwrap();
+        let results = session(&recorder);
+        recorder.stop();
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        (results, log)
+    }
+
+    #[test]
+    fn replay_gives_the_same_results() {
+        let (recorded, log) = record(Disk::default(), "round-trip");
+        assert!(log.starts_with(HEADER));
+        let replayer = Replayer::from_log(&log).unwrap();
+        assert_eq!(session(&replayer), recorded);
+        assert_eq!(replayer.divergence(), None);
+        assert_eq!(replayer.remaining(), 0);
+    }
+
+    #[test]
+    fn replay_diverges_on_a_different_call() {
+        let (_recorded, log) = record(Disk::default(), "diverge");
+        let replayer = Replayer::from_log(&log).unwrap();
+        assert_eq!(
+            replayer.open("HD0:/OTHER.TXT", file::Flags::empty()),
+            Err(Error::DeviceSpecific)
+        );
+        assert!(replayer.divergence().is_some());
+        // Once diverged, everything fails
+        assert_eq!(
+            replayer.open("HD0:/MISSING.TXT", file::Flags::empty()),
+            Err(Error::DeviceSpecific)
+        );
+    }
+
+    #[test]
+    fn replay_diverges_at_the_end_of_the_log() {
+        let replayer = Replayer::from_log(HEADER).unwrap();
+        assert_eq!(
+            replayer.close(file::Handle::new(3)),
+            Err(Error::DeviceSpecific)
+        );
+        assert!(replayer.divergence().is_some());
+    }
+
+    #[test]
+    fn bad_logs_are_rejected() {
+        assert!(Replayer::from_log("open(\"A\", 0x00) ok 3").is_err());
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn tracing_does_not_change_the_log() {
+        use crate::backend::trace::Tracer;
+        let (recorded, log) = record(Tracer::new(Disk::default()), "trace");
+        let (_, untraced_log) = record(Disk::default(), "untraced");
+        assert_eq!(log, untraced_log);
+        let replayer = Tracer::new(Replayer::from_log(&log).unwrap());
+        assert_eq!(session(&replayer), recorded);
+        assert_eq!(replayer.inner().divergence(), None);
+        assert_eq!(replayer.inner().remaining(), 0);
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
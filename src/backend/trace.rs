@@ -0,0 +1,507 @@
+//! Syscall tracing
+//!
+//! Wraps a [`Backend`] in a [`Tracer`], which logs every call the application
+//! makes - like `strace` on Linux. Each line shows the call, its arguments,
+//! what it returned, and how long it took:
+//!
+//! ```text
+//! [trace] open("HD0:/README.TXT", Flags(0x0)) -> Ok(3) in 120 us
+//! [trace] read(3, 64 bytes) -> Ok(64) in 85 us
+//! ```
+//!
+//! With the `trace` feature enabled, the SDK does this for you - on Neotron OS
+//! it wraps the OS's API table, and on UNIX and Windows it wraps the built-in
+//! fake OS. The log goes to Standard Error, or on UNIX and Windows to the file
+//! named in the `NEOTRON_TRACE_FILE` environment variable, if it is set.
+//!
+//! Timings come from [`time::Instant`](crate::time::Instant), which is
+//! called before and after every call, and are left off if it can't measure
+//! time. On Neotron OS, on a CPU without a DWT cycle counter (like the
+//! Cortex-M0+), that means tracing turns SysTick on if the OS hasn't - see
+//! [`time::Instant`](crate::time::Instant) for the details.
+//!
+//! If you want the log lines somewhere else, use [`Tracer::with_sink`].
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use core::fmt::{Debug, Write};
+
+use super::{dir, file, Backend};
+use crate::{entry_name, time::Instant, Result};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The longest trace line we produce. Anything longer is cut short.
+const MAX_LINE_LEN: usize = 192;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A [`Backend`] which passes calls on to another backend, and logs them.
+///
+/// On Neotron OS, log lines are written to Standard Error using the wrapped
+/// backend, so they aren't traced themselves. Put the tracer straight around
+/// the OS, and wrap it in a `Recorder` if you want both - that way the log
+/// lines aren't recorded (but you do see the recorder writing its log).
+///
+/// On UNIX and Windows, log lines always go straight to the host, never
+/// through the wrapped backend, so you can wrap anything - even a `Replayer`.
+pub struct Tracer<B> {
+    inner: B,
+    sink: Option<fn(&[u8])>,
+}
+
+impl<B: Backend> Tracer<B> {
+    /// Wrap a backend.
+    pub const fn new(inner: B) -> Tracer<B> {
+        Tracer { inner, sink: None }
+    }
+
+    /// Wrap a backend, and give each log line to `sink` instead of writing
+    /// it to Standard Error.
+    ///
+    /// Each line ends with a newline, and is at most 192 bytes long.
+    pub const fn with_sink(inner: B, sink: fn(&[u8])) -> Tracer<B> {
+        Tracer {
+            inner,
+            sink: Some(sink),
+        }
+    }
+
+    /// Get the wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Log a call that has finished.
+    fn log<T: Debug>(&self, start: Option<Instant>, call: core::fmt::Arguments, result: Result<T>) {
+        let mut line = Line::new();
+        let _ = match start {
+            Some(start) => writeln!(
+                line,
+                "[trace] {} -> {:?} in {} us",
+                call,
+                result,
+                start.elapsed().as_micros()
+            ),
+            None => writeln!(line, "[trace] {} -> {:?}", call, result),
+        };
+        self.emit(line.finish());
+    }
+
+    /// Give a log line to the sink, if we have one, or write it out.
+    fn emit(&self, line: &[u8]) {
+        match self.sink {
+            Some(sink) => sink(line),
+            None => self.write_line(line),
+        }
+    }
+
+    /// Write a log line to Standard Error.
+    #[cfg(target_os = "none")]
+    fn write_line(&self, line: &[u8]) {
+        let _ = self.inner.write(file::Handle::new_stderr(), line);
+    }
+
+    /// Write a log line to the trace file, or Standard Error.
+    #[cfg(not(target_os = "none"))]
+    fn write_line(&self, line: &[u8]) {
+        use std::io::Write;
+        static TRACE_FILE: std::sync::OnceLock<Option<std::sync::Mutex<std::fs::File>>> =
+            std::sync::OnceLock::new();
+        let trace_file = TRACE_FILE.get_or_init(|| {
+            let path = std::env::var_os("NEOTRON_TRACE_FILE")?;
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .ok()?;
+            Some(std::sync::Mutex::new(file))
+        });
+        match trace_file {
+            Some(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                let _ = file.write_all(line);
+            }
+            None => {
+                let _ = crate::fake_os_api::HostOs.write(file::Handle::new_stderr(), line);
+            }
+        }
+    }
+}
+
+impl<B: Backend> Backend for Tracer<B> {
+    fn open(&self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        let start = Instant::now();
+        let result = self.inner.open(path, flags);
+        let value = result.as_ref().map(|fd| fd.value()).map_err(|e| *e);
+        self.log(start, format_args!("open({:?}, {:?})", path, flags), value);
+        result
+    }
+
+    fn close(&self, fd: file::Handle) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.close(fd);
+        self.log(start, format_args!("close({})", fd.value()), result);
+        result
+    }
+
+    fn write(&self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.write(fd, buffer);
+        self.log(
+            start,
+            format_args!("write({}, {} bytes)", fd.value(), buffer.len()),
+            result,
+        );
+        result
+    }
+
+    fn read(&self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        let start = Instant::now();
+        let len = buffer.len();
+        let result = self.inner.read(fd, buffer);
+        self.log(
+            start,
+            format_args!("read({}, {} bytes)", fd.value(), len),
+            result,
+        );
+        result
+    }
+
+    fn seek_set(&self, fd: file::Handle, position: u64) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.seek_set(fd, position);
+        self.log(
+            start,
+            format_args!("seek_set({}, {})", fd.value(), position),
+            result,
+        );
+        result
+    }
+
+    fn seek_cur(&self, fd: file::Handle, offset: i64) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.seek_cur(fd, offset);
+        self.log(
+            start,
+            format_args!("seek_cur({}, {})", fd.value(), offset),
+            result,
+        );
+        result
+    }
+
+    fn seek_end(&self, fd: file::Handle) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.seek_end(fd);
+        self.log(start, format_args!("seek_end({})", fd.value()), result);
+        result
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.rename(old_path, new_path);
+        self.log(
+            start,
+            format_args!("rename({:?}, {:?})", old_path, new_path),
+            result,
+        );
+        result
+    }
+
+    fn ioctl(&self, fd: file::Handle, command: u64, value: u64) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.ioctl(fd, command, value);
+        self.log(
+            start,
+            format_args!("ioctl({}, {:#x}, {:#x})", fd.value(), command, value),
+            result,
+        );
+        result
+    }
+
+    fn opendir(&self, path: &str) -> Result<dir::Handle> {
+        let start = Instant::now();
+        let result = self.inner.opendir(path);
+        let value = result.as_ref().map(|dir| dir.value()).map_err(|e| *e);
+        self.log(start, format_args!("opendir({:?})", path), value);
+        result
+    }
+
+    fn closedir(&self, dir: dir::Handle) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.closedir(dir);
+        self.log(start, format_args!("closedir({})", dir.value()), result);
+        result
+    }
+
+    fn readdir(&self, dir: dir::Handle) -> Result<dir::Entry> {
+        let start = Instant::now();
+        let result = self.inner.readdir(dir);
+        let value = result.as_ref().map(entry_name).map_err(|e| *e);
+        self.log(start, format_args!("readdir({})", dir.value()), value);
+        result
+    }
+
+    fn stat(&self, path: &str) -> Result<file::Stat> {
+        let start = Instant::now();
+        let result = self.inner.stat(path);
+        let value = result.as_ref().map(StatSummary).map_err(|e| *e);
+        self.log(start, format_args!("stat({:?})", path), value);
+        result
+    }
+
+    fn fstat(&self, fd: file::Handle) -> Result<file::Stat> {
+        let start = Instant::now();
+        let result = self.inner.fstat(fd);
+        let value = result.as_ref().map(StatSummary).map_err(|e| *e);
+        self.log(start, format_args!("fstat({})", fd.value()), value);
+        result
+    }
+
+    fn deletefile(&self, path: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.deletefile(path);
+        self.log(start, format_args!("deletefile({:?})", path), result);
+        result
+    }
+
+    fn deletedir(&self, path: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.deletedir(path);
+        self.log(start, format_args!("deletedir({:?})", path), result);
+        result
+    }
+
+    fn chdir(&self, path: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.chdir(path);
+        self.log(start, format_args!("chdir({:?})", path), result);
+        result
+    }
+
+    fn dchdir(&self, dir: dir::Handle) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.dchdir(dir);
+        self.log(start, format_args!("dchdir({})", dir.value()), result);
+        result
+    }
+
+    fn pwd(&self, buffer: &mut [u8]) -> Result<usize> {
+        let start = Instant::now();
+        let len = buffer.len();
+        let result = self.inner.pwd(buffer);
+        self.log(start, format_args!("pwd({} bytes)", len), result);
+        result
+    }
+
+    fn malloc(&self, size: usize, alignment: usize) -> Result<*mut core::ffi::c_void> {
+        let start = Instant::now();
+        let result = self.inner.malloc(size, alignment);
+        self.log(
+            start,
+            format_args!("malloc({}, {})", size, alignment),
+            result,
+        );
+        result
+    }
+
+    fn free(&self, ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        let start = Instant::now();
+        self.inner.free(ptr, size, alignment);
+        self.log(
+            start,
+            format_args!("free({:p}, {}, {})", ptr, size, alignment),
+            Ok(()),
+        );
+    }
+}
+
+/// Shows the interesting parts of a [`file::Stat`].
+struct StatSummary<'a>(&'a file::Stat);
+
+impl Debug for StatSummary<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{{ size: {}, attr: {:?} }}",
+            self.0.file_size, self.0.attr
+        )
+    }
+}
+
+/// Somewhere to format a trace line, without allocating.
+///
+/// Text which doesn't fit is dropped, but the line always ends with a
+/// newline.
+struct Line {
+    buffer: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Line {
+        Line {
+            buffer: [0u8; MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        if self.len == MAX_LINE_LEN {
+            self.buffer[MAX_LINE_LEN - 1] = b'\n';
+        }
+        &self.buffer[0..self.len]
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let space = MAX_LINE_LEN - self.len;
+        let count = s.len().min(space);
+        self.buffer[self.len..self.len + count].copy_from_slice(&s.as_bytes()[0..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::cell::RefCell;
+
+    std::thread_local! {
+        /// The lines traced on this thread.
+        static LINES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Keeps a trace line, without its timing.
+    fn keep(line: &[u8]) {
+        let line = String::from_utf8(line.to_vec()).unwrap();
+        assert!(line.ends_with('\n'));
+        let line = match line.rsplit_once(" in ") {
+            Some((line, timing)) if timing.ends_with(" us\n") => line.to_owned(),
+            _ => line.trim_end().to_owned(),
+        };
+        LINES.with(|lines| lines.borrow_mut().push(line));
+    }
+
+    /// Get the lines traced on this thread so far.
+    fn take_lines() -> Vec<String> {
+        LINES.with(|lines| lines.take())
+    }
+
+    fn stat() -> file::Stat {
+        let time = file::Time {
+            year_since_1970: 54,
+            zero_indexed_month: 4,
+            zero_indexed_day: 18,
+            hours: 14,
+            minutes: 30,
+            seconds: 0,
+        };
+        file::Stat {
+            file_size: 12,
+            ctime: time,
+            mtime: time,
+            attr: file::Attributes::ARCHIVE,
+        }
+    }
+
+    /// A backend with one file, and one entry in every directory.
+    struct Disk;
+
+    impl Backend for Disk {
+        fn open(&self, path: &str, _flags: file::Flags) -> Result<file::Handle> {
+            if path == "HD0:/HELLO.TXT" {
+                Ok(file::Handle::new(3))
+            } else {
+                Err(Error::NotFound)
+            }
+        }
+
+        fn read(&self, _fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+            let n = buffer.len().min(5);
+            buffer[0..n].copy_from_slice(&b"Hello"[0..n]);
+            Ok(n)
+        }
+
+        fn readdir(&self, _dir: dir::Handle) -> Result<dir::Entry> {
+            let mut name = [0u8; 11];
+            name[0..9].copy_from_slice(b"HELLO.TXT");
+            Ok(dir::Entry {
+                name,
+                properties: stat(),
+            })
+        }
+
+        fn stat(&self, _path: &str) -> Result<file::Stat> {
+            Ok(stat())
+        }
+    }
+
+    #[test]
+    fn calls_are_traced_with_their_arguments() {
+        let tracer = Tracer::with_sink(Disk, keep);
+        take_lines();
+        let fd = tracer.open("HD0:/HELLO.TXT", file::Flags::WRITE).unwrap();
+        assert_eq!(
+            tracer.open("HD0:/NOPE.TXT", file::Flags::empty()),
+            Err(Error::NotFound)
+        );
+        assert_eq!(tracer.read(fd, &mut [0u8; 64]), Ok(5));
+        tracer.readdir(dir::Handle::new(1)).unwrap();
+        tracer.stat("HD0:/HELLO.TXT").unwrap();
+        assert_eq!(tracer.close(fd), Err(Error::Unimplemented));
+        assert_eq!(
+            take_lines(),
+            [
+                "[trace] open(\"HD0:/HELLO.TXT\", Flags(WRITE)) -> Ok(3)",
+                "[trace] open(\"HD0:/NOPE.TXT\", Flags(0x0)) -> Err(NotFound)",
+                "[trace] read(3, 64 bytes) -> Ok(5)",
+                "[trace] readdir(1) -> Ok(\"HELLO.TXT\")",
+                "[trace] stat(\"HD0:/HELLO.TXT\") -> Ok({ size: 12, attr: Attributes(ARCHIVE) })",
+                "[trace] close(3) -> Err(Unimplemented)",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_cut_short() {
+        let tracer = Tracer::with_sink(Disk, keep);
+        take_lines();
+        let path = "A".repeat(MAX_LINE_LEN * 2);
+        let _ = tracer.stat(&path);
+        let lines = take_lines();
+        assert_eq!(lines.len(), 1);
+        // `keep` trims the newline, which `Line::finish` put in the last byte
+        assert_eq!(lines[0].len(), MAX_LINE_LEN - 1);
+        assert!(lines[0].starts_with("[trace] stat(\"AAAA"));
+    }
+
+    #[test]
+    fn line_finish_ends_in_a_newline() {
+        let mut line = Line::new();
+        let _ = write!(line, "{}", "x".repeat(MAX_LINE_LEN + 10));
+        let finished = line.finish();
+        assert_eq!(finished.len(), MAX_LINE_LEN);
+        assert_eq!(finished[MAX_LINE_LEN - 1], b'\n');
+        let mut line = Line::new();
+        let _ = writeln!(line, "short");
+        assert_eq!(line.finish(), b"short\n");
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,578 @@
+//! Arm Cortex-M specific support code
+//!
+//! Contains a small `setjmp`/`longjmp` style trampoline, which runs the
+//! application on its own stack and lets the panic handler abandon the
+//! application and return straight to Neotron OS, the
+//! stack painting used to measure stack usage, cycle-accurate delays, and a
+//! monotonic cycle count for measuring time.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The CPU clock speed, in Hz.
+///
+/// Set at build time with the `NEOTRON_CPU_HZ` environment variable.
+pub const CPU_HZ: u32 = parse_u32(env!("NEOTRON_CPU_HZ"));
+
+/// SysTick Control and Status Register
+const SYST_CSR: *mut u32 = 0xE000_E010 as *mut u32;
+
+/// SysTick Reload Value Register
+const SYST_RVR: *mut u32 = 0xE000_E014 as *mut u32;
+
+/// SysTick Current Value Register
+const SYST_CVR: *mut u32 = 0xE000_E018 as *mut u32;
+
+/// CPUID Base Register
+const SCB_CPUID: *const u32 = 0xE000_ED00 as *const u32;
+
+/// Debug Exception and Monitor Control Register
+#[cfg(has_dwt_cyccnt)]
+const DCB_DEMCR: *mut u32 = 0xE000_EDFC as *mut u32;
+
+/// DWT Control Register
+#[cfg(has_dwt_cyccnt)]
+const DWT_CTRL: *mut u32 = 0xE000_1000 as *mut u32;
+
+/// DWT Cycle Count Register
+#[cfg(has_dwt_cyccnt)]
+const DWT_CYCCNT: *mut u32 = 0xE000_1004 as *mut u32;
+
+/// The shortest SysTick period, in CPU cycles, we can measure time with.
+///
+/// That's 10 ms - if the OS has SysTick wrapping more often than that, we'd
+/// have to be called far too often to keep track of it.
+const MIN_SYSTICK_PERIOD: u32 = CPU_HZ / 100;
+
+/// How many iterations of [`spin`] we time when calibrating it.
+const CALIBRATION_LOOPS: u32 = 256;
+
+/// The value we paint unused stack with.
+#[cfg(feature = "stack-usage")]
+const STACK_PAINT: u32 = 0xCCCC_CCCC;
+
+/// How much stack, below the current stack pointer, we leave unpainted when
+/// painting the stack.
+#[cfg(feature = "stack-usage")]
+const STACK_PAINT_HEADROOM: usize = 64;
+
+// ============================================================================
+// Static Variables
+// ============================================================================
+
+/// The stack pointer of the OS, saved after the OS's callee-saved registers
+/// were pushed.
+///
+/// Zero when `neotron_main` isn't running.
+static RETURN_SP: AtomicUsize = AtomicUsize::new(0);
+
+/// How many CPU cycles [`CALIBRATION_LOOPS`] iterations of [`spin`] take.
+///
+/// Zero if we haven't calibrated yet.
+static LOOP_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+/// Has [`cycles`] been called yet?
+static COUNTING: AtomicBool = AtomicBool::new(false);
+
+/// The value of the hardware counter the last time [`cycles`] was called.
+static LAST_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Upper 32 bits of the cycle count returned by [`cycles`].
+///
+/// We use two 32-bit atomics because Armv6-M doesn't have 64-bit atomics.
+static CYCLES_HI: AtomicU32 = AtomicU32::new(0);
+
+/// Lower 32 bits of the cycle count returned by [`cycles`].
+static CYCLES_LO: AtomicU32 = AtomicU32::new(0);
+
+/// Did we turn SysTick on, so [`restore_systick`] needs to turn it off again?
+static SYSTICK_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The SysTick Control and Status Register before we turned SysTick on.
+static SAVED_SYST_CSR: AtomicU32 = AtomicU32::new(0);
+
+/// The SysTick Reload Value Register before we turned SysTick on.
+static SAVED_SYST_RVR: AtomicU32 = AtomicU32::new(0);
+
+/// The top of the stack region we painted.
+///
+/// Zero if the stack wasn't painted.
+#[cfg(feature = "stack-usage")]
+static STACK_TOP: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    /// The end of the heap, and so the bottom of the application's stack
+    /// region, from the linker script.
+    #[cfg(feature = "stack-usage")]
+    #[link_name = "__neotron_heap_end"]
+    static STACK_LIMIT: u32;
+
+    /// The top of the application's stack region (and of the TPA), from the
+    /// linker script.
+    #[cfg(feature = "stack-usage")]
+    #[link_name = "__neotron_stack_top"]
+    static STACK_REGION_TOP: u32;
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+extern "C" {
+    /// Saves the callee-saved registers and the stack pointer, switches to
+    /// the application's stack, then calls [`run_main`].
+    fn neotron_sdk_call_main() -> i32;
+
+    /// Restores the stack pointer and registers saved by
+    /// `neotron_sdk_call_main`, and returns `code` from it.
+    fn neotron_sdk_abort_main(code: i32) -> !;
+}
+
+/// Call `neotron_main`, in a way that lets us return to the OS from a panic.
+///
+/// The application runs on its own stack, at the top of the TPA (see
+/// `__neotron_stack_top` in the linker script). We go back to the OS's stack
+/// when it returns, or when it is abandoned with [`abort_main`].
+pub fn call_main() -> i32 {
+    unsafe { neotron_sdk_call_main() }
+}
+
+/// Called by `neotron_sdk_call_main`, on the application's stack.
+extern "C" fn run_main() -> i32 {
+    #[cfg(feature = "stack-usage")]
+    paint_stack();
+    unsafe { crate::neotron_main() }
+}
+
+/// Return to the OS, as if `neotron_main` had returned `code`.
+///
+/// If `neotron_main` isn't running (e.g. we panicked before it was called),
+/// there is nowhere to go, so we spin forever.
+pub fn abort_main(code: i32) -> ! {
+    if RETURN_SP.load(Ordering::Relaxed) == 0 {
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+    unsafe { neotron_sdk_abort_main(code) }
+}
+
+/// Fill the unused stack, from the top of the heap up to (nearly) the current
+/// stack pointer, with a known value.
+///
+/// We only touch the application's own stack region, between
+/// `__neotron_heap_end` and `__neotron_stack_top`. If the stack pointer isn't
+/// in that region, we paint nothing, and [`stack_usage`] returns `None`.
+#[cfg(feature = "stack-usage")]
+#[inline(never)]
+pub fn paint_stack() {
+    let sp: usize;
+    unsafe {
+        core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    let (bottom, region_top) = stack_region();
+    if sp <= bottom || sp > region_top {
+        STACK_TOP.store(0, Ordering::Relaxed);
+        return;
+    }
+    let top = sp.saturating_sub(STACK_PAINT_HEADROOM) & !0x03;
+    let mut p = bottom as *mut u32;
+    while (p as usize) < top {
+        unsafe {
+            p.write_volatile(STACK_PAINT);
+            p = p.add(1);
+        }
+    }
+    STACK_TOP.store(region_top, Ordering::Relaxed);
+}
+
+/// Work out the most stack used since the stack was painted, in bytes.
+///
+/// This is measured from the top of the application's stack region. Returns
+/// `None` if the stack wasn't painted.
+#[cfg(feature = "stack-usage")]
+pub fn stack_usage() -> Option<usize> {
+    let top = STACK_TOP.load(Ordering::Relaxed);
+    if top == 0 {
+        return None;
+    }
+    let (bottom, _) = stack_region();
+    let mut p = bottom as *const u32;
+    while (p as usize) < top {
+        if unsafe { p.read_volatile() } != STACK_PAINT {
+            break;
+        }
+        p = unsafe { p.add(1) };
+    }
+    Some(top - p as usize)
+}
+
+/// Get the bottom and top of the application's stack region.
+#[cfg(feature = "stack-usage")]
+fn stack_region() -> (usize, usize) {
+    let bottom = core::ptr::addr_of!(STACK_LIMIT) as usize;
+    let top = core::ptr::addr_of!(STACK_REGION_TOP) as usize;
+    (bottom, top)
+}
+
+/// Busy-wait for the given number of CPU cycles.
+pub fn delay_cycles(cycles: u64) {
+    if cycle_counter_enabled() {
+        delay_cycles_counter(cycles);
+    } else {
+        let iterations = cycles * u64::from(CALIBRATION_LOOPS) / u64::from(loop_cycles());
+        let mut remaining = iterations;
+        while remaining > 0 {
+            let this_time = remaining.min(u64::from(u32::MAX));
+            spin(this_time as u32);
+            remaining -= this_time;
+        }
+    }
+}
+
+/// Busy-wait using the DWT cycle counter.
+#[cfg(has_dwt_cyccnt)]
+fn delay_cycles_counter(cycles: u64) {
+    let mut remaining = cycles;
+    while remaining > 0 {
+        // Keep well clear of the point where the counter wraps
+        let this_time = remaining.min(0x8000_0000) as u32;
+        let start = unsafe { DWT_CYCCNT.read_volatile() };
+        while unsafe { DWT_CYCCNT.read_volatile() }.wrapping_sub(start) < this_time {
+            core::hint::spin_loop();
+        }
+        remaining -= u64::from(this_time);
+    }
+}
+
+/// Busy-wait using the DWT cycle counter.
+///
+/// This CPU doesn't have one, so this is never called.
+#[cfg(not(has_dwt_cyccnt))]
+fn delay_cycles_counter(_cycles: u64) {
+    unreachable!()
+}
+
+/// Get the number of CPU cycles since we first called this function.
+///
+/// If the CPU has a DWT cycle counter, we extend it to 64 bits. Otherwise we
+/// use SysTick (turning it on if the OS hasn't - [`restore_systick`] turns it
+/// off again), and add up how far it has counted since we last looked.
+/// Either way, this must be called at least once per hardware counter period
+/// (2^32 CPU cycles for the cycle counter, or one SysTick period), or we will
+/// miss a wrap-around without knowing it.
+///
+/// Returns `None` if SysTick is running from some other clock, or with a
+/// period shorter than [`MIN_SYSTICK_PERIOD`], as then we can't measure time.
+pub fn cycles() -> Option<u64> {
+    let elapsed = cycles_since_last()?;
+    let hi = CYCLES_HI.load(Ordering::Relaxed);
+    let lo = CYCLES_LO.load(Ordering::Relaxed);
+    let total = (u64::from(hi) << 32) | u64::from(lo);
+    let total = total + u64::from(elapsed);
+    CYCLES_HI.store((total >> 32) as u32, Ordering::Relaxed);
+    CYCLES_LO.store(total as u32, Ordering::Relaxed);
+    Some(total)
+}
+
+/// Get the number of cycles since this function was last called.
+fn cycles_since_last() -> Option<u32> {
+    let (now, elapsed) = if cycle_counter_enabled() {
+        let last = LAST_COUNT.load(Ordering::Relaxed);
+        let now = read_cycle_counter();
+        // Counts up
+        (now, now.wrapping_sub(last))
+    } else {
+        let (now, reload) = read_systick()?;
+        let last = LAST_COUNT.load(Ordering::Relaxed);
+        // Counts down, from `reload` to zero
+        let elapsed = if last >= now {
+            last - now
+        } else {
+            last + (reload + 1) - now
+        };
+        (now, elapsed)
+    };
+    LAST_COUNT.store(now, Ordering::Relaxed);
+    let first_time = !COUNTING.load(Ordering::Relaxed);
+    COUNTING.store(true, Ordering::Relaxed);
+    if first_time {
+        Some(0)
+    } else {
+        Some(elapsed)
+    }
+}
+
+/// Read the DWT cycle counter.
+#[cfg(has_dwt_cyccnt)]
+fn read_cycle_counter() -> u32 {
+    unsafe { DWT_CYCCNT.read_volatile() }
+}
+
+/// Read the DWT cycle counter.
+///
+/// This CPU doesn't have one, so this is never called.
+#[cfg(not(has_dwt_cyccnt))]
+fn read_cycle_counter() -> u32 {
+    unreachable!()
+}
+
+/// Read SysTick, returning the current and reload values.
+///
+/// Turns SysTick on (with no interrupt) if it's off. Returns `None` if
+/// SysTick isn't counting CPU cycles, or wraps more often than
+/// [`MIN_SYSTICK_PERIOD`].
+fn read_systick() -> Option<(u32, u32)> {
+    let csr = unsafe { SYST_CSR.read_volatile() };
+    if (csr & 0b001) == 0 {
+        SAVED_SYST_CSR.store(csr, Ordering::Relaxed);
+        SAVED_SYST_RVR.store(unsafe { SYST_RVR.read_volatile() }, Ordering::Relaxed);
+        SYSTICK_STARTED.store(true, Ordering::Relaxed);
+        unsafe {
+            SYST_RVR.write_volatile(0x00FF_FFFF);
+            SYST_CVR.write_volatile(0);
+            // CLKSOURCE = CPU, ENABLE, no interrupt
+            SYST_CSR.write_volatile(0b101);
+        }
+    } else if (csr & 0b100) == 0 {
+        // Not counting CPU cycles
+        return None;
+    }
+    let reload = unsafe { SYST_RVR.read_volatile() } & 0x00FF_FFFF;
+    if reload + 1 < MIN_SYSTICK_PERIOD {
+        // Wraps too often to keep track of
+        return None;
+    }
+    let now = unsafe { SYST_CVR.read_volatile() };
+    Some((now, reload))
+}
+
+/// Put SysTick back the way the OS left it, if [`cycles`] turned it on.
+pub fn restore_systick() {
+    if SYSTICK_STARTED.load(Ordering::Relaxed) {
+        SYSTICK_STARTED.store(false, Ordering::Relaxed);
+        COUNTING.store(false, Ordering::Relaxed);
+        unsafe {
+            SYST_CSR.write_volatile(SAVED_SYST_CSR.load(Ordering::Relaxed));
+            SYST_RVR.write_volatile(SAVED_SYST_RVR.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Turn on the DWT cycle counter, if the CPU has one.
+///
+/// Returns `true` if the cycle counter is running.
+#[cfg(has_dwt_cyccnt)]
+pub fn cycle_counter_enabled() -> bool {
+    unsafe {
+        // Set TRCENA, to turn on the DWT
+        DCB_DEMCR.write_volatile(DCB_DEMCR.read_volatile() | (1 << 24));
+        let ctrl = DWT_CTRL.read_volatile();
+        if (ctrl & (1 << 25)) != 0 {
+            // NOCYCCNT is set - the cycle counter isn't implemented
+            return false;
+        }
+        if (ctrl & 1) == 0 {
+            // Set CYCCNTENA
+            DWT_CTRL.write_volatile(ctrl | 1);
+        }
+    }
+    true
+}
+
+/// Turn on the DWT cycle counter, if the CPU has one.
+///
+/// This CPU doesn't have one.
+#[cfg(not(has_dwt_cyccnt))]
+pub fn cycle_counter_enabled() -> bool {
+    false
+}
+
+/// Get how many CPU cycles [`CALIBRATION_LOOPS`] iterations of [`spin`] take,
+/// calibrating the loop if we haven't done so already.
+fn loop_cycles() -> u32 {
+    let cycles = LOOP_CYCLES.load(Ordering::Relaxed);
+    if cycles != 0 {
+        return cycles;
+    }
+    // Take the best of three, in case an interrupt fires while we're
+    // measuring.
+    let measured = (0..3).filter_map(|_| measure_loop_cycles()).min();
+    let cycles = measured.unwrap_or_else(|| {
+        // We couldn't measure it, so use what the Technical Reference Manual
+        // says. The Cortex-M0 takes three cycles for a taken branch, other
+        // cores take two.
+        let part_no = (unsafe { SCB_CPUID.read_volatile() } >> 4) & 0xFFF;
+        let cycles_per_loop = if part_no == 0xC20 { 4 } else { 3 };
+        cycles_per_loop * CALIBRATION_LOOPS
+    });
+    LOOP_CYCLES.store(cycles, Ordering::Relaxed);
+    cycles
+}
+
+/// Use SysTick to measure how many CPU cycles [`CALIBRATION_LOOPS`]
+/// iterations of [`spin`] take.
+///
+/// If SysTick is off, we turn it on for a moment. If it is running from
+/// something other than the CPU clock, or wraps too quickly, we give up.
+fn measure_loop_cycles() -> Option<u32> {
+    let csr = unsafe { SYST_CSR.read_volatile() };
+    let was_enabled = (csr & 0b001) != 0;
+    if was_enabled && (csr & 0b100) == 0 {
+        // Not counting CPU cycles
+        return None;
+    }
+    let rvr = unsafe { SYST_RVR.read_volatile() };
+    if !was_enabled {
+        unsafe {
+            SYST_RVR.write_volatile(0x00FF_FFFF);
+            SYST_CVR.write_volatile(0);
+            // CLKSOURCE = CPU, ENABLE, no interrupt
+            SYST_CSR.write_volatile(0b101);
+        }
+    }
+    let reload = unsafe { SYST_RVR.read_volatile() } & 0x00FF_FFFF;
+    let start = unsafe { SYST_CVR.read_volatile() };
+    spin(CALIBRATION_LOOPS);
+    let end = unsafe { SYST_CVR.read_volatile() };
+    if !was_enabled {
+        unsafe {
+            SYST_CSR.write_volatile(csr);
+            SYST_RVR.write_volatile(rvr);
+        }
+    }
+    if reload < CALIBRATION_LOOPS * 8 {
+        // Could have wrapped more than once
+        return None;
+    }
+    // SysTick counts down, and we might have wrapped once
+    let elapsed = if start >= end {
+        start - end
+    } else {
+        start + (reload + 1) - end
+    };
+    Some(elapsed)
+}
+
+/// Spin around a tight loop, the given number of times.
+#[inline(always)]
+fn spin(iterations: u32) {
+    if iterations == 0 {
+        return;
+    }
+    unsafe {
+        core::arch::asm!(
+            "2:",
+            "subs {0}, #1",
+            "bne 2b",
+            inout(reg) iterations => _,
+            options(nomem, nostack)
+        );
+    }
+}
+
+/// Parse a decimal integer at compile time.
+const fn parse_u32(s: &str) -> u32 {
+    let bytes = s.as_bytes();
+    let mut result: u32 = 0;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let digit = bytes[idx];
+        assert!(digit.is_ascii_digit(), "NEOTRON_CPU_HZ must be an integer");
+        result = result * 10 + (digit - b'0') as u32;
+        idx += 1;
+    }
+    result
+}
+
+// We save r4-r11 and lr (plus r3, to keep the stack 8-byte aligned) and, if
+// the FPU is in use, s16-s31. Only Armv6-M instructions are used for the
+// integer registers so this works on every Cortex-M.
+#[cfg(target_abi = "eabihf")]
+macro_rules! fpu_save {
+    () => {
+        ".fpu fpv4-sp-d16\nvpush {{s16-s31}}"
+    };
+}
+
+#[cfg(target_abi = "eabihf")]
+macro_rules! fpu_restore {
+    () => {
+        "vpop {{s16-s31}}"
+    };
+}
+
+#[cfg(not(target_abi = "eabihf"))]
+macro_rules! fpu_save {
+    () => {
+        ""
+    };
+}
+
+#[cfg(not(target_abi = "eabihf"))]
+macro_rules! fpu_restore {
+    () => {
+        ""
+    };
+}
+
+core::arch::global_asm!(
+    ".section .text.neotron_sdk_call_main,\"ax\",%progbits",
+    ".global neotron_sdk_call_main",
+    ".type neotron_sdk_call_main,%function",
+    ".thumb_func",
+    "neotron_sdk_call_main:",
+    "push {{r4-r7, lr}}",
+    "mov r4, r8",
+    "mov r5, r9",
+    "mov r6, r10",
+    "mov r7, r11",
+    "push {{r3-r7}}",
+    fpu_save!(),
+    "ldr r1, ={return_sp}",
+    "mov r2, sp",
+    "str r2, [r1]",
+    "ldr r2, =__neotron_stack_top",
+    "mov sp, r2",
+    "bl {main}",
+    "ldr r1, ={return_sp}",
+    "ldr r2, [r1]",
+    "mov sp, r2",
+    "movs r2, #0",
+    "str r2, [r1]",
+    "neotron_sdk_restore:",
+    fpu_restore!(),
+    "pop {{r3-r7}}",
+    "mov r8, r4",
+    "mov r9, r5",
+    "mov r10, r6",
+    "mov r11, r7",
+    "pop {{r4-r7, pc}}",
+    ".size neotron_sdk_call_main, . - neotron_sdk_call_main",
+    "",
+    ".global neotron_sdk_abort_main",
+    ".type neotron_sdk_abort_main,%function",
+    ".thumb_func",
+    "neotron_sdk_abort_main:",
+    "ldr r1, ={return_sp}",
+    "ldr r2, [r1]",
+    "mov sp, r2",
+    "movs r2, #0",
+    "str r2, [r1]",
+    "b neotron_sdk_restore",
+    ".size neotron_sdk_abort_main, . - neotron_sdk_abort_main",
+    ".ltorg",
+    return_sp = sym RETURN_SP,
+    main = sym run_main,
+);
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -2,40 +2,140 @@
 //!
 //! Allows Neotron SDK applications to run using libstd instead of Neotron OS
 
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::sync::{
-    mpsc::{channel, Receiver},
-    Mutex,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::{channel, Receiver, TryRecvError},
+    Mutex, OnceLock,
 };
 
+/// How much memory `malloc` will give out, if `NEOTRON_HEAP_SIZE` isn't set.
+///
+/// This is the size of the Transient Program Area on a Cortex-M Neotron, so
+/// an application can't have more than this on real hardware.
+const DEFAULT_HEAP_SIZE: usize = 256 * 1024;
+
+/// The value we paint unused stack with, when checking the TPA.
+const STACK_PAINT: u32 = 0xCCCC_CCCC;
+
+/// How much stack, below where we are when we paint it, we leave unpainted.
+const STACK_PAINT_HEADROOM: usize = 1024;
+
+/// How much stack the application gets on top of the TPA size, when checking
+/// the TPA.
+///
+/// Code built for the host uses more stack than code built for Arm, and we'd
+/// rather print a warning than crash.
+const EXTRA_STACK: usize = 1024 * 1024;
+
 static STDIN_RX: Mutex<Option<Receiver<u8>>> = Mutex::new(None);
 
-static FAKE_API: neotron_api::Api = neotron_api::Api {
-    open: api_open,
-    close: api_close,
-    write: api_write,
-    read: api_read,
-    seek_set: api_seek_set,
-    seek_cur: api_seek_cur,
-    seek_end: api_seek_end,
-    rename: api_rename,
-    ioctl: api_ioctl,
-    opendir: api_opendir,
-    closedir: api_closedir,
-    readdir: api_readdir,
-    stat: api_stat,
-    fstat: api_fstat,
-    deletefile: api_deletefile,
-    deletedir: api_deletedir,
-    chdir: api_chdir,
-    dchdir: api_dchdir,
-    pwd: api_pwd,
-    malloc: api_malloc,
-    free: api_free,
-};
+/// Is the host terminal in raw mode?
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+/// How many bytes `malloc` has given out, and not had back.
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// The backend which uses libstd.
+///
+/// Only Standard Input, Standard Output and Standard Error are supported.
+/// Everything else returns `Error::Unimplemented`.
+pub struct HostOs;
+
+impl crate::backend::Backend for HostOs {
+    /// Write to an open file handle, blocking until everything is written.
+    ///
+    /// Some files do not support writing and will produce an error.
+    fn write(&self, fd: neotron_api::file::Handle, buffer: &[u8]) -> crate::Result<()> {
+        let result = if fd == neotron_api::file::Handle::new_stdout() {
+            let stdout = std::io::stdout();
+            let translate = stdout.is_terminal();
+            write_translated(&mut stdout.lock(), buffer, translate)
+        } else if fd == neotron_api::file::Handle::new_stderr() {
+            let stderr = std::io::stderr();
+            let translate = stderr.is_terminal();
+            write_translated(&mut stderr.lock(), buffer, translate)
+        } else {
+            return Err(neotron_api::Error::BadHandle);
+        };
+        result.map_err(|_| neotron_api::Error::DeviceSpecific)
+    }
+
+    /// Read from an open file, returning how much was actually read.
+    ///
+    /// If you hit the end of the file, you might get less data than you asked for.
+    ///
+    /// Standard Input never blocks - you get `Ok(0)` if nothing has been typed
+    /// yet, and `Err(Error::EndOfFile)` once the host's stdin has closed and
+    /// everything has been read.
+    fn read(&self, fd: neotron_api::file::Handle, buffer: &mut [u8]) -> crate::Result<usize> {
+        if fd != neotron_api::file::Handle::new_stdin() {
+            return Err(neotron_api::Error::BadHandle);
+        }
+        let mut guard = STDIN_RX.lock().unwrap();
+        let Some(receiver) = guard.as_mut() else {
+            // Nobody called `start`
+            return Err(neotron_api::Error::EndOfFile);
+        };
+        let mut count = 0;
+        for slot in buffer.iter_mut() {
+            match receiver.try_recv() {
+                Ok(b) => {
+                    *slot = b;
+                    count += 1;
+                }
+                Err(TryRecvError::Empty) => {
+                    break;
+                }
+                Err(TryRecvError::Disconnected) if count == 0 => {
+                    return Err(neotron_api::Error::EndOfFile);
+                }
+                Err(TryRecvError::Disconnected) => {
+                    break;
+                }
+            }
+        }
+        Ok(count)
+    }
 
-/// Get an Api pointer that uses libstd.
-pub fn get_ptr() -> *const neotron_api::Api {
+    /// Allocate some memory from the host, up to a limit.
+    ///
+    /// The limit is set by the `NEOTRON_HEAP_SIZE` environment variable (like
+    /// `65536`, `64K` or `1M`), and defaults to 256 KiB.
+    fn malloc(&self, size: usize, alignment: usize) -> crate::Result<*mut core::ffi::c_void> {
+        let layout = std::alloc::Layout::from_size_align(size.max(1), alignment)
+            .map_err(|_| neotron_api::Error::InvalidArg)?;
+        let budget = heap_budget();
+        HEAP_USED
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(size).filter(|total| *total <= budget)
+            })
+            .map_err(|_| neotron_api::Error::OutOfMemory)?;
+        // Safety: the layout has a non-zero size
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        if ptr.is_null() {
+            HEAP_USED.fetch_sub(size, Ordering::Relaxed);
+            return Err(neotron_api::Error::OutOfMemory);
+        }
+        Ok(ptr as *mut core::ffi::c_void)
+    }
+
+    /// Free some memory from [`HostOs::malloc`].
+    fn free(&self, ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+        if ptr.is_null() {
+            return;
+        }
+        let Ok(layout) = std::alloc::Layout::from_size_align(size.max(1), alignment) else {
+            return;
+        };
+        // Safety: the application must give back what `malloc` gave it
+        unsafe { std::alloc::dealloc(ptr as *mut u8, layout) };
+        HEAP_USED.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+/// Start reading from the host's stdin, in a background thread.
+pub fn start() {
     let (sender, receiver) = channel();
     *STDIN_RX.lock().unwrap() = Some(receiver);
 
@@ -44,201 +144,207 @@ pub fn get_ptr() -> *const neotron_api::Api {
         let mut stdin = std::io::stdin();
         loop {
             let mut buffer = [0u8; 1];
-            if let Ok(1) = stdin.read(&mut buffer) {
-                sender.send(buffer[0]).unwrap();
+            match stdin.read(&mut buffer) {
+                Ok(0) => {
+                    // End of input. Dropping the sender tells `HostOs::read`.
+                    break;
+                }
+                Ok(_) => {
+                    if sender.send(buffer[0]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    // Try again
+                }
+                Err(_) => {
+                    break;
+                }
             }
         }
     });
-
-    &FAKE_API as *const neotron_api::Api
 }
 
-/// Open a file, given a path as UTF-8 string.
+/// Put the host terminal into raw mode, if Standard Input is a terminal.
 ///
-/// If the file does not exist, or is already open, it returns an error.
-///
-/// Path may be relative to current directory, or it may be an absolute
-/// path.
-extern "C" fn api_open(
-    _path: neotron_api::FfiString,
-    _flags: neotron_api::file::Flags,
-) -> neotron_api::Result<neotron_api::file::Handle> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Close a previously opened file.
-extern "C" fn api_close(_fd: neotron_api::file::Handle) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// If it isn't a terminal (e.g. input is piped in), we leave it alone.
+pub fn enable_raw_mode() {
+    if std::io::stdin().is_terminal() && crossterm::terminal::enable_raw_mode().is_ok() {
+        RAW_MODE.store(true, Ordering::Relaxed);
+    }
 }
 
-/// Write to an open file handle, blocking until everything is written.
-///
-/// Some files do not support writing and will produce an error.
-extern "C" fn api_write(
-    fd: neotron_api::file::Handle,
-    buffer: neotron_api::FfiByteSlice,
-) -> neotron_api::Result<()> {
-    if fd == neotron_api::file::Handle::new_stdout() {
-        let mut stdout = std::io::stdout();
-        let buffer = buffer.as_slice();
-        for chunk in buffer.split_inclusive(|b| *b == b'\n') {
-            if chunk.last() == Some(&b'\n') {
-                // raw terminal needs CR and LF
-                stdout.write_all(&chunk[0..chunk.len() - 1]).unwrap();
-                stdout.write_all(b"\r\n").unwrap();
-            } else {
-                stdout.write_all(chunk).unwrap();
-            }
-        }
-        stdout.flush().unwrap();
-        neotron_api::Result::Ok(())
-    } else {
-        neotron_api::Result::Err(neotron_api::Error::BadHandle)
+/// Take the host terminal out of raw mode, if we put it in raw mode.
+pub fn disable_raw_mode() {
+    if RAW_MODE.swap(false, Ordering::Relaxed) {
+        let _ = crossterm::terminal::disable_raw_mode();
     }
 }
 
-/// Read from an open file, returning how much was actually read.
+/// Write to the host's stdout or stderr.
 ///
-/// If you hit the end of the file, you might get less data than you asked for.
-extern "C" fn api_read(
-    fd: neotron_api::file::Handle,
-    mut buffer: neotron_api::FfiBuffer,
-) -> neotron_api::Result<usize> {
-    if fd == neotron_api::file::Handle::new_stdin() {
-        if let Ok(b) = STDIN_RX.lock().unwrap().as_mut().unwrap().try_recv() {
-            buffer.as_mut_slice().unwrap()[0] = b;
-            neotron_api::Result::Ok(1)
+/// If `is_terminal` is set and the terminal is in raw mode, each LF is turned
+/// into CR LF, because a raw terminal needs both. Otherwise the bytes are
+/// passed through unchanged.
+fn write_translated(out: &mut dyn Write, buffer: &[u8], is_terminal: bool) -> std::io::Result<()> {
+    if !(is_terminal && RAW_MODE.load(Ordering::Relaxed)) {
+        out.write_all(buffer)?;
+        return out.flush();
+    }
+    for chunk in buffer.split_inclusive(|b| *b == b'\n') {
+        if chunk.last() == Some(&b'\n') {
+            out.write_all(&chunk[0..chunk.len() - 1])?;
+            out.write_all(b"\r\n")?;
         } else {
-            neotron_api::Result::Ok(0)
+            out.write_all(chunk)?;
         }
-    } else {
-        neotron_api::Result::Err(neotron_api::Error::BadHandle)
     }
+    out.flush()
 }
 
-/// Move the file offset (for the given file handle) to the given position.
+/// Run the application.
 ///
-/// Some files do not support seeking and will produce an error.
-extern "C" fn api_seek_set(
-    _fd: neotron_api::file::Handle,
-    _position: u64,
-) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Move the file offset (for the given file handle) relative to the current position
+/// If the `NEOTRON_TPA_SIZE` environment variable is set (like `256K`), the
+/// application runs on a thread with a painted stack. When it finishes, we
+/// work out how much stack it used, add on the size of the static data (on
+/// Linux), and print a warning if that wouldn't fit in a Transient Program
+/// Area of that size.
 ///
-/// Some files do not support seeking and will produce an error.
-extern "C" fn api_seek_cur(
-    _fd: neotron_api::file::Handle,
-    _offset: i64,
-) -> neotron_api::Result<u64> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Move the file offset (for the given file handle) to the end of the file
-///
-/// Some files do not support seeking and will produce an error.
-extern "C" fn api_seek_end(_fd: neotron_api::file::Handle) -> neotron_api::Result<u64> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Rename a file
-extern "C" fn api_rename(
-    _old_path: neotron_api::FfiString,
-    _new_path: neotron_api::FfiString,
-) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Perform a special I/O control operation.
-extern "C" fn api_ioctl(
-    _fd: neotron_api::file::Handle,
-    _command: u64,
-    _value: u64,
-) -> neotron_api::Result<u64> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Open a directory, given a path as a UTF-8 string.
-extern "C" fn api_opendir(
-    _path: neotron_api::FfiString,
-) -> neotron_api::Result<neotron_api::dir::Handle> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// This is only a rough guide - code built for the host is not the same as
+/// code built for Arm, and a host binary contains some of libstd's static
+/// data too.
+pub fn run_main<F: FnOnce() -> i32 + Send>(main: F) -> i32 {
+    let Some(tpa_size) = env_size("NEOTRON_TPA_SIZE") else {
+        return main();
+    };
+    let outcome = std::thread::scope(|s| {
+        std::thread::Builder::new()
+            .stack_size(tpa_size + EXTRA_STACK)
+            .spawn_scoped(s, || {
+                let painted = paint_stack(tpa_size);
+                let res = main();
+                (res, painted.used())
+            })
+            .expect("spawning application thread")
+            .join()
+    });
+    let (res, stack_used) = match outcome {
+        Ok(outcome) => outcome,
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+    let static_size = static_size();
+    let total = static_size + stack_used;
+    if total > tpa_size {
+        disable_raw_mode();
+        eprintln!(
+            "warning: application needs about {} bytes ({} bytes of static data and {} bytes of stack), but the TPA is only {} bytes",
+            total, static_size, stack_used, tpa_size
+        );
+    }
+    res
 }
 
-/// Close a previously opened directory.
-extern "C" fn api_closedir(_dir: neotron_api::dir::Handle) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// How much memory `malloc` may give out.
+fn heap_budget() -> usize {
+    static HEAP_BUDGET: OnceLock<usize> = OnceLock::new();
+    *HEAP_BUDGET.get_or_init(|| env_size("NEOTRON_HEAP_SIZE").unwrap_or(DEFAULT_HEAP_SIZE))
 }
 
-/// Read from an open directory
-extern "C" fn api_readdir(
-    _dir: neotron_api::dir::Handle,
-) -> neotron_api::Result<neotron_api::dir::Entry> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// Read a size in bytes from an environment variable.
+///
+/// Accepts decimal, or hex with a `0x` prefix, with an optional `K` or `M`
+/// suffix for KiB or MiB. If the variable isn't a size, we say so and ignore
+/// it.
+fn env_size(name: &str) -> Option<usize> {
+    let value = std::env::var(name).ok()?;
+    let size = parse_size(&value);
+    if size.is_none() {
+        eprintln!("warning: ignoring {}={:?}, as it isn't a size", name, value);
+    }
+    size
 }
 
-/// Get information about a file
-extern "C" fn api_stat(
-    _path: neotron_api::FfiString,
-) -> neotron_api::Result<neotron_api::file::Stat> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// Parse a size like `65536`, `0x10000`, `64K` or `1M`.
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (digits, multiplier) = if let Some(digits) = value.strip_suffix(['K', 'k']) {
+        (digits, 1024)
+    } else if let Some(digits) = value.strip_suffix(['M', 'm']) {
+        (digits, 1024 * 1024)
+    } else {
+        (value, 1)
+    };
+    let number = if let Some(hex) = digits.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()?
+    } else {
+        digits.parse::<usize>().ok()?
+    };
+    number.checked_mul(multiplier)
 }
 
-/// Get information about an open file
-extern "C" fn api_fstat(
-    _fd: neotron_api::file::Handle,
-) -> neotron_api::Result<neotron_api::file::Stat> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// A painted region of stack.
+struct PaintedStack {
+    /// The lowest painted address
+    bottom: usize,
+    /// Just above the highest painted address
+    top: usize,
 }
 
-/// Delete a file.
-///
-/// If the file is currently open this will give an error.
-extern "C" fn api_deletefile(_path: neotron_api::FfiString) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+impl PaintedStack {
+    /// How much of the painted stack has been used since it was painted.
+    fn used(&self) -> usize {
+        let mut p = self.bottom as *const u32;
+        while (p as usize) < self.top {
+            // Safety: we painted this memory, so it's part of our stack
+            if unsafe { p.read_volatile() } != STACK_PAINT {
+                break;
+            }
+            p = unsafe { p.add(1) };
+        }
+        self.top - (p as usize) + STACK_PAINT_HEADROOM
+    }
 }
 
-/// Delete a directory
+/// Paint `len` bytes of stack below the current stack frame.
 ///
-/// If the directory has anything in it, this will give an error.
-extern "C" fn api_deletedir(_path: neotron_api::FfiString) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// Only call this at the top of a thread with at least `len` bytes of stack
+/// to spare.
+#[inline(never)]
+fn paint_stack(len: usize) -> PaintedStack {
+    let marker = 0u8;
+    let here = std::hint::black_box(core::ptr::addr_of!(marker)) as usize;
+    let top = here.saturating_sub(STACK_PAINT_HEADROOM) & !0x03;
+    let bottom = top - (len & !0x03);
+    let mut p = bottom as *mut u32;
+    while (p as usize) < top {
+        // Safety: the thread was created with enough stack, and nothing below
+        // us is in use yet
+        unsafe {
+            p.write_volatile(STACK_PAINT);
+            p = p.add(1);
+        }
+    }
+    PaintedStack { bottom, top }
 }
 
-/// Change the current directory
-///
-/// Relative file paths are taken to be relative to the current directory.
-///
-/// Unlike on MS-DOS, there is only one current directory for the whole
-/// system, not one per drive.
-extern "C" fn api_chdir(_path: neotron_api::FfiString) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// How big the static data (`.data` and `.bss`) is.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn static_size() -> usize {
+    extern "C" {
+        /// Start of `.data`, from glibc
+        static __data_start: u8;
+        /// End of `.bss`, from the linker
+        static end: u8;
+    }
+    let start = core::ptr::addr_of!(__data_start) as usize;
+    let finish = core::ptr::addr_of!(end) as usize;
+    finish.saturating_sub(start)
 }
 
-/// Change the current directory to the open directory
+/// How big the static data is.
 ///
-/// Relative file paths are taken to be relative to the current directory.
-///
-/// Unlike on MS-DOS, there is only one current directory for the whole
-/// system, not one per drive.
-extern "C" fn api_dchdir(_dir: neotron_api::dir::Handle) -> neotron_api::Result<()> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Obtain the current working directory.
-extern "C" fn api_pwd(_path: neotron_api::FfiBuffer) -> neotron_api::Result<usize> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
-}
-
-/// Allocate some memory
-extern "C" fn api_malloc(
-    _size: usize,
-    _alignment: usize,
-) -> neotron_api::Result<*mut core::ffi::c_void> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+/// We don't know how to find out on this host, so we only check the stack.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn static_size() -> usize {
+    0
 }
-
-/// Free some previously allocated memory
-extern "C" fn api_free(_ptr: *mut core::ffi::c_void, _size: usize, _alignment: usize) {}
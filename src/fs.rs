@@ -0,0 +1,761 @@
+//! Filesystem helpers
+//!
+//! Higher-level operations built on top of [`File`](crate::File) and
+//! [`ReadDir`], which don't need any heap allocation.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use crate::{api, entry_name, path, path::PathBuf, Error, File, Flags, ReadDir, Result};
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// The order in which [`WalkDir`] returns a directory and its contents.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Order {
+    /// Return each directory before its contents.
+    PreOrder,
+    /// Return each directory after its contents.
+    PostOrder,
+}
+
+/// An entry found by [`WalkDir`].
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// The full path to this entry.
+    pub path: PathBuf,
+    /// How deep this entry is. The contents of the root directory have a
+    /// depth of 1.
+    pub depth: usize,
+    /// The directory entry, including the name and properties.
+    pub entry: api::dir::Entry,
+}
+
+impl WalkEntry {
+    /// Is this entry a directory?
+    pub fn is_dir(&self) -> bool {
+        is_dir(&self.entry)
+    }
+}
+
+/// An iterator which recursively walks a directory tree.
+///
+/// Create one with [`walk_dir`]. It holds open up to `N` directory handles at
+/// once (one for each level of the tree it is currently inside), so it can go
+/// at most `N` levels deep. If it finds a directory it can't go into because
+/// `N` directories are already open, it returns `Err(Error::OutOfMemory)` and
+/// carries on without it.
+///
+/// The `.` and `..` entries are never returned.
+pub struct WalkDir<const N: usize> {
+    /// The directories we are reading, outermost first.
+    stack: [Option<ReadDir>; N],
+    /// For each directory in `stack`, the entry describing it (if it isn't the
+    /// root). Used for post-order traversal.
+    dir_entries: [Option<api::dir::Entry>; N],
+    /// How many items in `stack` are in use.
+    open: usize,
+    /// The path of the innermost directory we are reading.
+    path: PathBuf,
+    /// A directory we just returned (pre-order), and will go into next time,
+    /// unless [`WalkDir::skip_current_dir`] is called.
+    pending: Option<PathBuf>,
+    /// Don't return entries deeper than this.
+    max_depth: usize,
+    /// Which order to return directories in.
+    order: Order,
+}
+
+impl<const N: usize> WalkDir<N> {
+    /// Set the order in which directories are returned.
+    ///
+    /// The default is [`Order::PreOrder`].
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Stop walking the current directory.
+    ///
+    /// If the last entry returned was a directory that we would otherwise go
+    /// into next (which only happens in pre-order), we skip that directory.
+    /// Otherwise we skip the rest of the directory we are currently reading.
+    pub fn skip_current_dir(&mut self) {
+        if self.pending.take().is_none() && self.open > 0 {
+            // We don't return a skipped directory, even in post-order
+            let _ = self.close_innermost();
+        }
+    }
+
+    /// Open a directory, and make it the innermost one.
+    fn descend(&mut self, path: PathBuf, entry: Option<api::dir::Entry>) -> Result<()> {
+        if self.open == N {
+            return Err(Error::OutOfMemory);
+        }
+        let dir = ReadDir::open(path.as_path()?)?;
+        self.stack[self.open] = Some(dir);
+        self.dir_entries[self.open] = entry;
+        self.open += 1;
+        self.path = path;
+        Ok(())
+    }
+
+    /// Close the innermost directory.
+    ///
+    /// Returns the entry for that directory, which should be returned if
+    /// we're in post-order.
+    fn close_innermost(&mut self) -> Option<WalkEntry> {
+        self.open -= 1;
+        self.stack[self.open] = None;
+        let item = self.dir_entries[self.open].take().map(|entry| WalkEntry {
+            path: self.path.clone(),
+            depth: self.open,
+            entry,
+        });
+        self.path.pop();
+        item
+    }
+}
+
+impl<const N: usize> Iterator for WalkDir<N> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(path) = self.pending.take() {
+            if let Err(e) = self.descend(path, None) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            if self.open == 0 {
+                return None;
+            }
+            let depth = self.open;
+            let next_entry = self.stack[depth - 1].as_mut().and_then(|dir| dir.next());
+            let entry = match next_entry {
+                None => {
+                    let item = self.close_innermost();
+                    if self.order == Order::PostOrder {
+                        if let Some(item) = item {
+                            return Some(Ok(item));
+                        }
+                    }
+                    continue;
+                }
+                Some(Err(e)) => {
+                    return Some(Err(e));
+                }
+                Some(Ok(entry)) => entry,
+            };
+            let name = entry_name(&entry);
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = match self.path.join(name) {
+                Ok(path) => path,
+                Err(e) => return Some(Err(e)),
+            };
+            let will_descend = is_dir(&entry) && depth < self.max_depth;
+            match (will_descend, self.order) {
+                (true, Order::PreOrder) => {
+                    self.pending = Some(path.clone());
+                }
+                (true, Order::PostOrder) => {
+                    // We return this directory when we've finished with it
+                    if let Err(e) = self.descend(path, Some(entry)) {
+                        return Some(Err(e));
+                    }
+                    continue;
+                }
+                (false, _) => {}
+            }
+            return Some(Ok(WalkEntry { path, depth, entry }));
+        }
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Recursively walk the directory tree starting at `root`.
+///
+/// The iterator returns every file and directory in the tree (but not `root`
+/// itself), along with its full path and depth. Entries more than `max_depth`
+/// levels deep are not returned, so a `max_depth` of `1` just lists `root`,
+/// and a `max_depth` of `0` returns nothing (but `root` must still exist).
+///
+/// `N` is the maximum number of directory handles to hold open at once. See
+/// [`WalkDir`] for more details.
+///
+/// ```no_run
+/// use core::fmt::Write;
+/// # fn main() -> neotron_sdk::Result<()> {
+/// let root = neotron_sdk::path::Path::new("HD0:/")?;
+/// let mut stdout = neotron_sdk::stdout();
+/// for item in neotron_sdk::fs::walk_dir::<4>(root, 4)? {
+///     let item = item?;
+///     let _ = writeln!(stdout, "{}", item.path);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn walk_dir<const N: usize>(root: path::Path, max_depth: usize) -> Result<WalkDir<N>> {
+    let mut walker = WalkDir {
+        stack: core::array::from_fn(|_| None),
+        dir_entries: core::array::from_fn(|_| None),
+        open: 0,
+        path: PathBuf::new(),
+        pending: None,
+        max_depth,
+        order: Order::PreOrder,
+    };
+    walker.descend(root.as_str().parse()?, None)?;
+    if max_depth == 0 {
+        // Nothing in `root` is shallow enough to return
+        let _ = walker.close_innermost();
+    }
+    Ok(walker)
+}
+
+/// Copy a file, using `buffer` to hold the data as it is copied.
+///
+/// The destination file is created if it doesn't exist, and replaced if it
+/// does. Returns the number of bytes copied, which is always the size of the
+/// source file. A bigger buffer means fewer, larger, reads and writes.
+///
+/// If anything goes wrong after the destination file has been opened, we try
+/// to delete the partially written destination file before returning the
+/// error.
+///
+/// Returns [`Error::InvalidArg`] if `src` and `dst` are the same file (once
+/// `.` and `..` are resolved, and ignoring case), because opening the
+/// destination would truncate the source before we had read it.
+///
+/// ```no_run
+/// use neotron_sdk::path::Path;
+/// # fn main() -> neotron_sdk::Result<()> {
+/// let mut buffer = [0u8; 512];
+/// neotron_sdk::fs::copy(Path::new("HD0:/README.TXT")?, Path::new("HD1:/README.TXT")?, &mut buffer)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn copy(src: path::Path, dst: path::Path, buffer: &mut [u8]) -> Result<u64> {
+    if buffer.is_empty() || same_file(&src, &dst)? {
+        return Err(Error::InvalidArg);
+    }
+    let src_file = File::open(src, Flags::empty())?;
+    let size = src_file.stat()?.file_size;
+    let dst_file = File::open(
+        reborrow(&dst)?,
+        Flags::WRITE | Flags::CREATE | Flags::TRUNCATE,
+    )?;
+    let result = match copy_contents(&src_file, &dst_file, size, buffer) {
+        Ok(()) => dst_file.close(),
+        Err(e) => {
+            // Close it, so we can delete it
+            drop(dst_file);
+            Err(e)
+        }
+    };
+    match result {
+        Ok(()) => Ok(size),
+        Err(e) => {
+            let _ = crate::delete(dst);
+            Err(e)
+        }
+    }
+}
+
+/// Move a file, which may be on a different drive.
+///
+/// We try [`File::rename`] first. If that fails, and the two paths are on
+/// different drives, we [`copy`] the file (using `buffer`) and then delete the
+/// source file. If the source file can't be deleted, we delete the copy, so
+/// the file is never in both places at once.
+///
+/// Relative paths are assumed to be on the current drive.
+pub fn move_file(src: path::Path, dst: path::Path, buffer: &mut [u8]) -> Result<()> {
+    let rename_error = match File::rename(reborrow(&src)?, reborrow(&dst)?) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    if same_drive(&src, &dst) {
+        return Err(rename_error);
+    }
+    copy(reborrow(&src)?, reborrow(&dst)?, buffer)?;
+    if let Err(e) = crate::delete(src) {
+        let _ = crate::delete(dst);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Delete a directory, and everything in it.
+///
+/// Files are deleted as they are found, and each directory is deleted once
+/// everything in it has gone, so this can go at most `N` directories deep (see
+/// [`WalkDir`]). Before each file or directory is deleted, `progress` is called
+/// with its path. We stop at the first error, which may leave some of the tree
+/// deleted and some not.
+///
+/// If `dry_run` is `true`, nothing is actually deleted, but `progress` is
+/// still called for everything that would have been.
+///
+/// Returns how many files and directories were deleted (including `path`
+/// itself).
+///
+/// ```no_run
+/// use core::fmt::Write;
+/// use neotron_sdk::path::Path;
+/// # fn main() -> neotron_sdk::Result<()> {
+/// let mut stdout = neotron_sdk::stdout();
+/// neotron_sdk::fs::remove_dir_all::<8>(Path::new("HD0:/TEMP")?, false, |path| {
+///     let _ = writeln!(stdout, "Deleting {}", path);
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn remove_dir_all<const N: usize>(
+    path: path::Path,
+    dry_run: bool,
+    mut progress: impl FnMut(&str),
+) -> Result<usize> {
+    let mut count = 0;
+    for item in walk_dir::<N>(reborrow(&path)?, usize::MAX)?.order(Order::PostOrder) {
+        let item = item?;
+        progress(item.path.as_str());
+        if !dry_run {
+            if item.is_dir() {
+                crate::delete_dir(item.path.as_path()?)?;
+            } else {
+                crate::delete(item.path.as_path()?)?;
+            }
+        }
+        count += 1;
+    }
+    progress(path.as_str());
+    if !dry_run {
+        crate::delete_dir(path)?;
+    }
+    Ok(count + 1)
+}
+
+/// Copy `size` bytes from one open file to another.
+fn copy_contents(src: &File, dst: &File, size: u64, buffer: &mut [u8]) -> Result<()> {
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk_len = buffer
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let count = src.read(&mut buffer[0..chunk_len])?;
+        if count == 0 {
+            // The file is shorter than it said it was
+            return Err(Error::EndOfFile);
+        }
+        dst.write(&buffer[0..count])?;
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+/// Are these two paths on the same drive?
+///
+/// Drive names are not case-sensitive. Paths without a drive are on the
+/// current drive, which we can only compare with other paths without a drive.
+fn same_drive(a: &path::Path, b: &path::Path) -> bool {
+    match (a.drive_specifier(), b.drive_specifier()) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Do these two paths name the same file?
+///
+/// We resolve any `.` and `..` components first, and FAT file names are not
+/// case-sensitive.
+fn same_file(a: &path::Path, b: &path::Path) -> Result<bool> {
+    let a: PathBuf = a.as_str().parse()?;
+    let b: PathBuf = b.as_str().parse()?;
+    Ok(a.as_str().eq_ignore_ascii_case(b.as_str()))
+}
+
+/// Make another copy of a path, as [`path::Path`] isn't `Copy`.
+///
+/// This can't fail, because the path was already checked when it was made.
+fn reborrow<'a>(path: &'a path::Path) -> Result<path::Path<'a>> {
+    path::Path::new(path.as_str())
+}
+
+/// Is this directory entry a directory?
+fn is_dir(entry: &api::dir::Entry) -> bool {
+    entry
+        .properties
+        .attr
+        .contains(api::file::Attributes::DIRECTORY)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fault::{Faults, Syscall};
+    use crate::testing::TestOs;
+
+    /// Walk `root` under `os`, returning each path with its depth.
+    fn walk<const N: usize>(
+        os: TestOs,
+        root: &str,
+        max_depth: usize,
+        order: Order,
+    ) -> Vec<Result<(String, usize)>> {
+        let mut found = Vec::new();
+        let output = os.run(|| {
+            let walker = walk_dir::<N>(path::Path::new(root)?, max_depth)?.order(order);
+            for item in walker {
+                found.push(item.map(|item| (item.path.as_str().to_owned(), item.depth)));
+            }
+            Ok::<(), Error>(())
+        });
+        assert_eq!(output.exit_code, 0);
+        found
+    }
+
+    #[test]
+    fn walk_dir_max_depth_zero_returns_nothing() {
+        let os = TestOs::new().file("HD0:/TOP/A.TXT", b"");
+        assert!(walk::<4>(os, "HD0:/TOP", 0, Order::PreOrder).is_empty());
+    }
+
+    #[test]
+    fn walk_dir_max_depth_one_lists_root() {
+        let os = TestOs::new()
+            .file("HD0:/TOP/A.TXT", b"")
+            .file("HD0:/TOP/SUB/B.TXT", b"");
+        let found = walk::<4>(os, "HD0:/TOP", 1, Order::PreOrder);
+        let found: Vec<_> = found.into_iter().map(|item| item.unwrap()).collect();
+        assert_eq!(
+            found,
+            [
+                ("HD0:/TOP/SUB".to_owned(), 1),
+                ("HD0:/TOP/A.TXT".to_owned(), 1)
+            ]
+        );
+    }
+
+    /// A small tree, three levels deep.
+    fn tree() -> TestOs {
+        TestOs::new()
+            .file("HD0:/TOP/A.TXT", b"")
+            .file("HD0:/TOP/SUB/B.TXT", b"")
+            .file("HD0:/TOP/SUB/DEEP/C.TXT", b"")
+    }
+
+    /// Unwrap every item from [`walk`].
+    fn unwrap_all(found: Vec<Result<(String, usize)>>) -> Vec<(String, usize)> {
+        found.into_iter().map(|item| item.unwrap()).collect()
+    }
+
+    #[test]
+    fn walk_dir_pre_order() {
+        let found = unwrap_all(walk::<4>(tree(), "HD0:/TOP", usize::MAX, Order::PreOrder));
+        assert_eq!(
+            found,
+            [
+                ("HD0:/TOP/SUB".to_owned(), 1),
+                ("HD0:/TOP/SUB/DEEP".to_owned(), 2),
+                ("HD0:/TOP/SUB/DEEP/C.TXT".to_owned(), 3),
+                ("HD0:/TOP/SUB/B.TXT".to_owned(), 2),
+                ("HD0:/TOP/A.TXT".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_dir_post_order() {
+        let found = unwrap_all(walk::<4>(tree(), "HD0:/TOP", usize::MAX, Order::PostOrder));
+        assert_eq!(
+            found,
+            [
+                ("HD0:/TOP/SUB/DEEP/C.TXT".to_owned(), 3),
+                ("HD0:/TOP/SUB/DEEP".to_owned(), 2),
+                ("HD0:/TOP/SUB/B.TXT".to_owned(), 2),
+                ("HD0:/TOP/SUB".to_owned(), 1),
+                ("HD0:/TOP/A.TXT".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_dir_max_depth_limits_both_orders() {
+        for order in [Order::PreOrder, Order::PostOrder] {
+            let found = unwrap_all(walk::<4>(tree(), "HD0:/TOP", 2, order));
+            assert!(found.iter().all(|(_, depth)| *depth <= 2));
+            assert_eq!(found.len(), 4);
+        }
+    }
+
+    #[test]
+    fn walk_dir_skip_current_dir() {
+        let mut found = Vec::new();
+        let output = tree().run(|| {
+            let mut walker = walk_dir::<4>(path::Path::new("HD0:/TOP")?, usize::MAX)?;
+            while let Some(item) = walker.next() {
+                let item = item?;
+                if item.path.as_str() == "HD0:/TOP/SUB/DEEP" {
+                    // Skips the directory we were about to go into
+                    walker.skip_current_dir();
+                } else if item.path.as_str() == "HD0:/TOP/SUB/B.TXT" {
+                    // Skips the rest of `SUB`
+                    walker.skip_current_dir();
+                }
+                found.push(item.path.as_str().to_owned());
+            }
+            Ok::<(), Error>(())
+        });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(
+            found,
+            [
+                "HD0:/TOP/SUB",
+                "HD0:/TOP/SUB/DEEP",
+                "HD0:/TOP/SUB/B.TXT",
+                "HD0:/TOP/A.TXT",
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_dir_out_of_memory_when_too_deep() {
+        let found = walk::<2>(tree(), "HD0:/TOP", usize::MAX, Order::PreOrder);
+        let found: Vec<_> = found
+            .into_iter()
+            .map(|item| item.map(|(path, _depth)| path))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                Ok("HD0:/TOP/SUB".to_owned()),
+                Ok("HD0:/TOP/SUB/DEEP".to_owned()),
+                Err(Error::OutOfMemory),
+                Ok("HD0:/TOP/SUB/B.TXT".to_owned()),
+                Ok("HD0:/TOP/A.TXT".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn copy_copies_in_chunks() {
+        let output = TestOs::new()
+            .file("HD0:/SRC.TXT", b"Hello, world")
+            .faults(Faults::new().short_reads(5))
+            .run(|| {
+                let mut buffer = [0u8; 4];
+                let size = copy(
+                    path::Path::new("HD0:/SRC.TXT")?,
+                    path::Path::new("HD1:/DST.TXT")?,
+                    &mut buffer,
+                )?;
+                assert_eq!(size, 12);
+                Ok::<(), Error>(())
+            });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.file("HD1:/DST.TXT"), Some(&b"Hello, world"[..]));
+        assert_eq!(output.file("HD0:/SRC.TXT"), Some(&b"Hello, world"[..]));
+    }
+
+    #[test]
+    fn copy_needs_a_buffer() {
+        let output = TestOs::new().file("HD0:/SRC.TXT", b"Hello").run(|| {
+            copy(
+                path::Path::new("HD0:/SRC.TXT")?,
+                path::Path::new("HD0:/DST.TXT")?,
+                &mut [],
+            )
+            .map(|_size| ())
+        });
+        assert_eq!(output.exit_code, 1);
+        assert_eq!(output.file("HD0:/DST.TXT"), None);
+    }
+
+    #[test]
+    fn copy_onto_itself() {
+        let mut result = None;
+        let output = TestOs::new().file("HD0:/SRC.TXT", b"Hello, world").run(|| {
+            let mut buffer = [0u8; 4];
+            result = Some(copy(
+                path::Path::new("HD0:/SRC.TXT")?,
+                path::Path::new("hd0:/./TMP/../src.txt")?,
+                &mut buffer,
+            ));
+            Ok::<(), Error>(())
+        });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(result, Some(Err(Error::InvalidArg)));
+        assert_eq!(output.file("HD0:/SRC.TXT"), Some(&b"Hello, world"[..]));
+    }
+
+    #[test]
+    fn copy_deletes_a_partial_copy() {
+        let mut result = None;
+        let output = TestOs::new()
+            .file("HD0:/SRC.TXT", b"Hello, world")
+            .faults(Faults::new().fail_nth(Syscall::Write, 2, Error::DeviceSpecific))
+            .run(|| {
+                let mut buffer = [0u8; 4];
+                result = Some(copy(
+                    path::Path::new("HD0:/SRC.TXT")?,
+                    path::Path::new("HD0:/DST.TXT")?,
+                    &mut buffer,
+                ));
+                Ok::<(), Error>(())
+            });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(result, Some(Err(Error::DeviceSpecific)));
+        assert_eq!(output.file("HD0:/DST.TXT"), None);
+        assert_eq!(output.file("HD0:/SRC.TXT"), Some(&b"Hello, world"[..]));
+    }
+
+    /// Move `HD0:/SRC.TXT` to `dst` under `os`, returning the result.
+    fn try_move(os: TestOs, dst: &str) -> (Result<()>, crate::testing::Output) {
+        let mut result = None;
+        let output = os.file("HD0:/SRC.TXT", b"Hello").run(|| {
+            let mut buffer = [0u8; 4];
+            result = Some(move_file(
+                path::Path::new("HD0:/SRC.TXT")?,
+                path::Path::new(dst)?,
+                &mut buffer,
+            ));
+            Ok::<(), Error>(())
+        });
+        assert_eq!(output.exit_code, 0);
+        (result.unwrap(), output)
+    }
+
+    #[test]
+    fn move_file_renames() {
+        let (result, output) = try_move(TestOs::new(), "HD0:/DST.TXT");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output.file("HD0:/SRC.TXT"), None);
+        assert_eq!(output.file("HD0:/DST.TXT"), Some(&b"Hello"[..]));
+    }
+
+    #[test]
+    fn move_file_on_one_drive_only_renames() {
+        let faults = Faults::new().fail_nth(Syscall::Rename, 1, Error::NotFound);
+        let (result, output) = try_move(TestOs::new().faults(faults), "HD0:/DST.TXT");
+        assert_eq!(result, Err(Error::NotFound));
+        assert_eq!(output.file("HD0:/SRC.TXT"), Some(&b"Hello"[..]));
+        assert_eq!(output.file("HD0:/DST.TXT"), None);
+    }
+
+    #[test]
+    fn move_file_copies_between_drives() {
+        let faults = Faults::new().fail_nth(Syscall::Rename, 1, Error::InvalidArg);
+        let (result, output) = try_move(TestOs::new().faults(faults), "HD1:/DST.TXT");
+        assert_eq!(result, Ok(()));
+        assert_eq!(output.file("HD0:/SRC.TXT"), None);
+        assert_eq!(output.file("HD1:/DST.TXT"), Some(&b"Hello"[..]));
+    }
+
+    #[test]
+    fn move_file_keeps_the_source_if_the_copy_fails() {
+        let faults = Faults::new()
+            .fail_nth(Syscall::Rename, 1, Error::InvalidArg)
+            .fail_nth(Syscall::Write, 2, Error::DeviceSpecific);
+        let (result, output) = try_move(TestOs::new().faults(faults), "HD1:/DST.TXT");
+        assert_eq!(result, Err(Error::DeviceSpecific));
+        assert_eq!(output.file("HD0:/SRC.TXT"), Some(&b"Hello"[..]));
+        assert_eq!(output.file("HD1:/DST.TXT"), None);
+    }
+
+    #[test]
+    fn move_file_deletes_the_copy_if_the_source_stays() {
+        let faults = Faults::new()
+            .fail_nth(Syscall::Rename, 1, Error::InvalidArg)
+            .fail_nth(Syscall::Deletefile, 1, Error::DeviceSpecific);
+        let (result, output) = try_move(TestOs::new().faults(faults), "HD1:/DST.TXT");
+        assert_eq!(result, Err(Error::DeviceSpecific));
+        assert_eq!(output.file("HD0:/SRC.TXT"), Some(&b"Hello"[..]));
+        assert_eq!(output.file("HD1:/DST.TXT"), None);
+    }
+
+    /// Remove `HD0:/TOP` from [`tree`], returning the result and every path
+    /// given to `progress`.
+    fn try_remove<const N: usize>(
+        faults: Faults,
+        dry_run: bool,
+    ) -> (Result<usize>, Vec<String>, crate::testing::Output) {
+        let mut result = None;
+        let mut progress = Vec::new();
+        let output = tree().faults(faults).run(|| {
+            result = Some(remove_dir_all::<N>(
+                path::Path::new("HD0:/TOP")?,
+                dry_run,
+                |path| progress.push(path.to_owned()),
+            ));
+            Ok::<(), Error>(())
+        });
+        assert_eq!(output.exit_code, 0);
+        (result.unwrap(), progress, output)
+    }
+
+    /// Everything in [`tree`], in the order `remove_dir_all` deletes it.
+    const TREE_POST_ORDER: [&str; 6] = [
+        "HD0:/TOP/SUB/DEEP/C.TXT",
+        "HD0:/TOP/SUB/DEEP",
+        "HD0:/TOP/SUB/B.TXT",
+        "HD0:/TOP/SUB",
+        "HD0:/TOP/A.TXT",
+        "HD0:/TOP",
+    ];
+
+    #[test]
+    fn remove_dir_all_deletes_everything() {
+        let (result, progress, output) = try_remove::<4>(Faults::new(), false);
+        assert_eq!(result, Ok(6));
+        assert_eq!(progress, TREE_POST_ORDER);
+        assert!(!output.dir_exists("HD0:/TOP"));
+        assert_eq!(output.file("HD0:/TOP/A.TXT"), None);
+        assert_eq!(output.file("HD0:/TOP/SUB/DEEP/C.TXT"), None);
+    }
+
+    #[test]
+    fn remove_dir_all_dry_run_deletes_nothing() {
+        let (result, progress, output) = try_remove::<4>(Faults::new(), true);
+        assert_eq!(result, Ok(6));
+        assert_eq!(progress, TREE_POST_ORDER);
+        assert!(output.dir_exists("HD0:/TOP/SUB/DEEP"));
+        assert_eq!(output.file("HD0:/TOP/A.TXT"), Some(&b""[..]));
+        assert_eq!(output.file("HD0:/TOP/SUB/DEEP/C.TXT"), Some(&b""[..]));
+    }
+
+    #[test]
+    fn remove_dir_all_stops_at_the_first_error() {
+        let faults = Faults::new().fail_nth(Syscall::Deletefile, 2, Error::DeviceSpecific);
+        let (result, progress, output) = try_remove::<4>(faults, false);
+        assert_eq!(result, Err(Error::DeviceSpecific));
+        assert_eq!(progress, TREE_POST_ORDER[0..3]);
+        assert!(!output.dir_exists("HD0:/TOP/SUB/DEEP"));
+        assert_eq!(output.file("HD0:/TOP/SUB/B.TXT"), Some(&b""[..]));
+        assert_eq!(output.file("HD0:/TOP/A.TXT"), Some(&b""[..]));
+    }
+
+    #[test]
+    fn remove_dir_all_too_deep() {
+        let (result, _progress, output) = try_remove::<2>(Faults::new(), false);
+        assert_eq!(result, Err(Error::OutOfMemory));
+        assert!(output.dir_exists("HD0:/TOP/SUB/DEEP"));
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,143 @@
+//! The application's heap
+//!
+//! The linker script sets aside the part of the Transient Program Area
+//! between the end of your static data and the bottom of the stack as a heap.
+//! The stack gets the top 16 KiB, unless you set the `NEOTRON_STACK_SIZE`
+//! environment variable (like `8K`) when you build, and the SDK switches to it
+//! before calling your application. The heap can't grow into the stack, but
+//! nothing stops the stack growing down into the heap if you use more than
+//! you reserved.
+//!
+//! With the `allocator` feature enabled, the SDK puts a linked-list allocator
+//! over this region and makes it the global allocator, so you can use the
+//! `alloc` crate (`Box`, `Vec`, `String`, etc). This works even if the OS
+//! doesn't implement the `malloc` call.
+//!
+//! ```no_run
+//! #![no_std]
+//! extern crate alloc;
+//!
+//! fn make_list() -> alloc::vec::Vec<u32> {
+//!     alloc::vec![1, 2, 3]
+//! }
+//! ```
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+#[cfg(feature = "allocator")]
+use core::{alloc::Layout, cell::RefCell, ptr::NonNull};
+
+// ============================================================================
+// Static Variables
+// ============================================================================
+
+extern "C" {
+    /// The start of the heap, from the linker script.
+    static __neotron_heap_start: u8;
+    /// The end of the heap, from the linker script.
+    static __neotron_heap_end: u8;
+}
+
+/// The SDK's global allocator.
+#[cfg(feature = "allocator")]
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator {
+    heap: RefCell::new(None),
+};
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A linked-list allocator over the heap region.
+///
+/// The heap is set up the first time something is allocated.
+#[cfg(feature = "allocator")]
+struct Allocator {
+    heap: RefCell<Option<linked_list_allocator::Heap>>,
+}
+
+// Safety: Neotron applications only have one thread, and the OS doesn't call
+// us from an interrupt. If we are re-entered anyway, the `RefCell` is already
+// borrowed, and the allocation fails instead.
+#[cfg(feature = "allocator")]
+unsafe impl Sync for Allocator {}
+
+#[cfg(feature = "allocator")]
+impl Allocator {
+    /// Run `f` with the heap, setting it up if this is the first time.
+    ///
+    /// Returns `None` if the heap is already in use.
+    fn with_heap<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut linked_list_allocator::Heap) -> T,
+    {
+        let mut heap = self.heap.try_borrow_mut().ok()?;
+        let heap = heap.get_or_insert_with(|| {
+            let mut heap = linked_list_allocator::Heap::empty();
+            if size() != 0 {
+                // Safety: the linker script gives us this memory, and nothing
+                // else uses it
+                unsafe { heap.init(start(), size()) };
+            }
+            heap
+        });
+        Some(f(heap))
+    }
+}
+
+#[cfg(feature = "allocator")]
+unsafe impl core::alloc::GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| heap.allocate_first_fit(layout).ok())
+            .flatten()
+            .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            // Safety: the caller promises this came from `alloc`, with this
+            // layout
+            self.with_heap(|heap| unsafe { heap.deallocate(ptr, layout) });
+        }
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Get the address of the start of the heap.
+pub fn start() -> *mut u8 {
+    core::ptr::addr_of!(__neotron_heap_start) as *mut u8
+}
+
+/// Get the address just past the end of the heap.
+///
+/// The stack is above this address.
+pub fn end() -> *mut u8 {
+    core::ptr::addr_of!(__neotron_heap_end) as *mut u8
+}
+
+/// Get the size of the heap, in bytes.
+pub fn size() -> usize {
+    (end() as usize).saturating_sub(start() as usize)
+}
+
+/// Get how many bytes of the heap have been allocated.
+#[cfg(feature = "allocator")]
+pub fn used() -> usize {
+    ALLOCATOR.with_heap(|heap| heap.used()).unwrap_or(0)
+}
+
+/// Get how many bytes of the heap are free.
+#[cfg(feature = "allocator")]
+pub fn free() -> usize {
+    ALLOCATOR.with_heap(|heap| heap.free()).unwrap_or(0)
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -14,6 +14,27 @@
 //! ([`app_entry()`](app_entry)). Once initialised, the SDK then expects you
 //! application to provide an `extern "C"` `no-mangle` function called
 //! `neotron_main`, which the SDK will call.
+//!
+//! The [`entry!`] macro will generate `neotron_main` (and a `main` function
+//! for UNIX and Windows) for you:
+//!
+//! ```no_run
+//! #![cfg_attr(target_os = "none", no_std)]
+//! #![cfg_attr(target_os = "none", no_main)]
+//!
+//! neotron_sdk::entry!(real_main);
+//!
+//! fn real_main() -> Result<(), neotron_sdk::Error> {
+//!     neotron_sdk::stdout().write(b"Hello, world\n")?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! On Arm, the SDK links your application with its own linker script, which
+//! puts it in the Transient Program Area (TPA) of a Neotron Pico. For another
+//! board, enable its `board-*` feature, or set the `NEOTRON_TPA_START` and
+//! `NEOTRON_TPA_SIZE` environment variables (like `0x20001000` and `256K`)
+//! when you build.
 
 #![cfg_attr(target_os = "none", no_std)]
 
@@ -25,11 +46,25 @@ use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 pub use neotron_ffi::{FfiBuffer, FfiByteSlice, FfiString};
 
-pub use neotron_api::{file::Flags, path, Api, Error};
+pub use neotron_api::{file::Flags, Api, Error};
 
 use neotron_api as api;
 
+pub mod backend;
 pub mod console;
+pub mod fs;
+pub mod path;
+pub mod random;
+pub mod time;
+
+#[cfg(not(target_os = "none"))]
+pub mod testing;
+
+#[cfg(all(target_os = "none", target_arch = "arm"))]
+mod cortex_m;
+
+#[cfg(all(target_os = "none", target_arch = "arm"))]
+pub mod heap;
 
 #[cfg(not(target_os = "none"))]
 mod fake_os_api;
@@ -43,6 +78,12 @@ mod fake_os_api;
 /// This is shorter than on MS-DOS, to save on memory.
 pub const MAX_PATH_LEN: usize = 64;
 
+/// The exit code returned to the OS when the application panics.
+///
+/// This is the same value a Rust program on UNIX or Windows exits with when
+/// it panics.
+pub const PANIC_EXIT_CODE: i32 = 101;
+
 extern "C" {
     /// This is what the user's application entry point must be called.
     ///
@@ -65,8 +106,25 @@ static ARG_COUNT: AtomicUsize = AtomicUsize::new(0);
 /// Start of the argument list
 static ARG_PTR: AtomicPtr<FfiString> = AtomicPtr::new(core::ptr::null_mut());
 
-/// Random number generator state
-static RAND_STATE: core::sync::atomic::AtomicU16 = core::sync::atomic::AtomicU16::new(0);
+/// The user's panic hook, if any, as a [`PanicHook`].
+static PANIC_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The OS's API table, wrapped in a tracer and a recorder.
+///
+/// The tracer goes inside the recorder, so the trace lines aren't recorded.
+#[cfg(all(target_os = "none", feature = "record", feature = "trace"))]
+static WRAPPED_OS: backend::record::Recorder<backend::trace::Tracer<backend::ApiBackend>> =
+    backend::record::Recorder::new(backend::trace::Tracer::new(backend::ApiBackend::new()));
+
+/// The OS's API table, wrapped in a recorder.
+#[cfg(all(target_os = "none", feature = "record", not(feature = "trace")))]
+static WRAPPED_OS: backend::record::Recorder<backend::ApiBackend> =
+    backend::record::Recorder::new(backend::ApiBackend::new());
+
+/// The OS's API table, wrapped in a tracer.
+#[cfg(all(feature = "trace", not(all(target_os = "none", feature = "record"))))]
+static WRAPPED_OS: backend::trace::Tracer<backend::ApiBackend> =
+    backend::trace::Tracer::new(backend::ApiBackend::new());
 
 // ============================================================================
 // Types
@@ -82,6 +140,19 @@ pub use neotron_api::AppStartFn;
 /// Like a [`core::result::Result`] but the error type is [`Error`].
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// The information passed to a panic hook.
+#[cfg(target_os = "none")]
+pub type PanicInfo<'a> = core::panic::PanicInfo<'a>;
+
+/// The information passed to a panic hook.
+#[cfg(not(target_os = "none"))]
+pub type PanicInfo<'a> = std::panic::PanicHookInfo<'a>;
+
+/// A function called when the application panics.
+///
+/// See [`set_panic_hook`].
+pub type PanicHook = fn(&PanicInfo);
+
 /// Represents an open File
 pub struct File(api::file::Handle);
 
@@ -189,7 +260,7 @@ impl File {
     ///
     /// * You cannot rename a file if it is currently open.
     /// * You cannot rename a file where the `old_path` and the `new_path` are
-    /// not on the same drive.
+    ///   not on the same drive.
     /// * Paths must confirm to the rules for the filesystem for the given drive.
     pub fn rename(old_path: path::Path, new_path: path::Path) -> Result<()> {
         let api = get_api();
@@ -250,30 +321,103 @@ impl core::fmt::Write for &File {
 }
 
 /// Represents an open directory that we are iterating through.
-pub struct ReadDir(api::dir::Handle);
+///
+/// The iterator finishes when the OS reports `Error::EndOfFile`, or after it
+/// has returned any other error.
+pub struct ReadDir {
+    handle: api::dir::Handle,
+    finished: bool,
+}
 
 impl ReadDir {
     pub fn open(path: path::Path) -> Result<ReadDir> {
         let api = get_api();
         match (api.opendir)(FfiString::new(path.as_str())) {
-            neotron_ffi::FfiResult::Ok(output) => Ok(ReadDir(output)),
+            neotron_ffi::FfiResult::Ok(output) => Ok(ReadDir {
+                handle: output,
+                finished: false,
+            }),
             neotron_ffi::FfiResult::Err(e) => Err(e),
         }
     }
+
+    /// Only return the entries whose names match the given wildcard pattern,
+    /// like `*.TXT` or `DATA??.BIN`.
+    ///
+    /// See [`path::glob_match`] for the rules. Errors are always returned.
+    pub fn filter_glob(self, pattern: &str) -> impl Iterator<Item = Result<api::dir::Entry>> + '_ {
+        self.filter(move |item| match item {
+            Ok(entry) => path::glob_match(pattern, entry_name(entry)),
+            Err(_) => true,
+        })
+    }
 }
 
 impl Iterator for ReadDir {
     type Item = Result<api::dir::Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        None
+        if self.finished {
+            return None;
+        }
+        let api = get_api();
+        match (api.readdir)(self.handle) {
+            neotron_ffi::FfiResult::Ok(entry) => Some(Ok(entry)),
+            neotron_ffi::FfiResult::Err(Error::EndOfFile) => {
+                self.finished = true;
+                None
+            }
+            neotron_ffi::FfiResult::Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
     }
 }
 
 impl Drop for ReadDir {
     fn drop(&mut self) {
         let api = get_api();
-        let _ = (api.closedir)(self.0);
+        let _ = (api.closedir)(self.handle);
+    }
+}
+
+/// Converts the value returned by an application's main function into an exit
+/// code for the OS.
+///
+/// Used by the [`entry!`] macro. Errors are printed to Standard Output before
+/// an exit code of `1` is returned.
+pub trait Termination {
+    /// Report this value, and convert it into an exit code.
+    fn report(self) -> i32;
+}
+
+impl Termination for i32 {
+    fn report(self) -> i32 {
+        self
+    }
+}
+
+impl Termination for () {
+    fn report(self) -> i32 {
+        0
+    }
+}
+
+impl<E> Termination for core::result::Result<(), E>
+where
+    E: core::fmt::Debug,
+{
+    fn report(self) -> i32 {
+        use core::fmt::Write;
+        match self {
+            Ok(()) => 0,
+            Err(e) => {
+                let mut stdout = stdout();
+                let _ = writeln!(stdout, "Error: {:?}", e);
+                1
+            }
+        }
     }
 }
 
@@ -286,6 +430,48 @@ pub enum WaitForKey {
     Quit,
 }
 
+// ============================================================================
+// Macros
+// ============================================================================
+
+/// Generate the entry points for your application.
+///
+/// Give it the path to a function which takes no arguments and returns either
+/// an `i32` exit code, `()`, or a `Result<(), E>` where `E` implements
+/// `Debug`. See [`Termination`] for how the return value is converted into an
+/// exit code.
+///
+/// This macro generates the `extern "C"` `neotron_main` function that
+/// [`app_entry()`](app_entry) calls, and on UNIX and Windows it also
+/// generates a `main` function which calls [`init()`](init). You still need
+/// to mark your crate as `no_std` and `no_main` when building for Neotron OS.
+///
+/// ```no_run
+/// #![cfg_attr(target_os = "none", no_std)]
+/// #![cfg_attr(target_os = "none", no_main)]
+///
+/// neotron_sdk::entry!(real_main);
+///
+/// fn real_main() -> i32 {
+///     // Your code here
+///     0
+/// }
+/// ```
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[cfg(not(target_os = "none"))]
+        fn main() {
+            $crate::init();
+        }
+
+        #[no_mangle]
+        extern "C" fn neotron_main() -> i32 {
+            $crate::Termination::report($main())
+        }
+    };
+}
+
 // ============================================================================
 // Functions
 // ============================================================================
@@ -294,15 +480,78 @@ pub enum WaitForKey {
 ///
 /// Will initialise the SDK and then jump to the application entry point, which
 /// is an `extern "C"` function called `neotron_main`.
+///
+/// On Arm, the OS's stack pointer is saved before `neotron_main` is called, so
+/// that if the application panics we can return to the OS with
+/// [`PANIC_EXIT_CODE`]. The application then runs on its own stack, at the top
+/// of the Transient Program Area.
 #[no_mangle]
 pub extern "C" fn app_entry(api: *const Api, argc: usize, argv: *const FfiString) -> i32 {
     let _check: AppStartFn = app_entry;
     API.store(api as *mut Api, Ordering::Relaxed);
+    #[cfg(any(feature = "trace", all(target_os = "none", feature = "record")))]
+    wrap_os(api);
     ARG_COUNT.store(argc, Ordering::Relaxed);
     ARG_PTR.store(argv as *mut FfiString, Ordering::Relaxed);
+    let result = call_main();
+    #[cfg(all(target_os = "none", feature = "record"))]
+    WRAPPED_OS.stop();
+    #[cfg(feature = "stack-usage-report")]
+    report_stack_usage();
+    #[cfg(all(target_os = "none", target_arch = "arm"))]
+    cortex_m::restore_systick();
+    result
+}
+
+/// Trace and/or record every call the application makes to the OS.
+#[cfg(any(feature = "trace", all(target_os = "none", feature = "record")))]
+fn wrap_os(api: *const Api) {
+    // Safety: the OS promises the table lives as long as we do
+    let Some(api) = (unsafe { api.as_ref() }) else {
+        return;
+    };
+    #[cfg(all(target_os = "none", feature = "record", feature = "trace"))]
+    WRAPPED_OS.inner().inner().set(api);
+    #[cfg(not(all(target_os = "none", feature = "record", feature = "trace")))]
+    WRAPPED_OS.inner().set(api);
+    // If we can't open the log, the application still runs - it just isn't
+    // recorded.
+    #[cfg(all(target_os = "none", feature = "record"))]
+    let _ = WRAPPED_OS.start(option_env!("NEOTRON_RECORD_FILE").unwrap_or("RECORD.LOG"));
+    // Safety: the application hasn't started yet, so nothing else is making
+    // OS calls.
+    unsafe { backend::install(&WRAPPED_OS) };
+}
+
+/// Call the application's `neotron_main` function.
+#[cfg(all(target_os = "none", target_arch = "arm"))]
+fn call_main() -> i32 {
+    cortex_m::call_main()
+}
+
+/// Call the application's `neotron_main` function.
+#[cfg(not(all(target_os = "none", target_arch = "arm")))]
+fn call_main() -> i32 {
     unsafe { neotron_main() }
 }
 
+/// Abandon the application and return to the OS with the given exit code.
+///
+/// Only works if we were called from [`app_entry()`](app_entry), otherwise it
+/// spins forever.
+#[cfg(target_os = "none")]
+fn abort_main(code: i32) -> ! {
+    #[cfg(target_arch = "arm")]
+    cortex_m::abort_main(code);
+    #[cfg(not(target_arch = "arm"))]
+    {
+        let _ = code;
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+}
+
 /// Get a command line argument.
 ///
 /// Given an zero-based index, returns `Some(str)` if that argument was
@@ -328,18 +577,44 @@ pub fn arg(n: usize) -> Option<String> {
     std::env::args().skip(1).nth(n)
 }
 
-/// Get information about a file on disk.
+/// Get the name of a directory entry.
 ///
-/// **Note:** This function is not implemented currently.
-pub fn stat(_path: path::Path) -> Result<api::file::Stat> {
-    todo!()
+/// Any NUL or space padding at the end of the name is removed. Names should be
+/// ASCII - if there are any bytes which aren't valid UTF-8, the name is cut
+/// short before them.
+pub fn entry_name(entry: &api::dir::Entry) -> &str {
+    let name = match core::str::from_utf8(&entry.name) {
+        Ok(name) => name,
+        Err(e) => {
+            // Safety: `valid_up_to` tells us this much is valid UTF-8
+            unsafe { core::str::from_utf8_unchecked(&entry.name[0..e.valid_up_to()]) }
+        }
+    };
+    name.trim_end_matches(['\0', ' '])
+}
+
+/// Get information about a file on disk.
+pub fn stat(path: path::Path) -> Result<api::file::Stat> {
+    let api = get_api();
+    (api.stat)(FfiString::new(path.as_str())).into()
 }
 
 /// Delete a file from disk
 ///
-/// **Note:** This function is not implemented currently.
-pub fn delete(_path: path::Path) -> Result<()> {
-    todo!()
+/// You cannot delete a file if it is currently open.
+pub fn delete(path: path::Path) -> Result<()> {
+    let api = get_api();
+    (api.deletefile)(FfiString::new(path.as_str())).into()
+}
+
+/// Delete an empty directory from disk
+///
+/// You cannot delete the root directory of a drive, or a directory with
+/// anything in it. See [`fs::remove_dir_all`] if you want to delete everything
+/// in it too.
+pub fn delete_dir(path: path::Path) -> Result<()> {
+    let api = get_api();
+    (api.deletedir)(FfiString::new(path.as_str())).into()
 }
 
 /// Change the current working directory to the given path.
@@ -365,16 +640,19 @@ pub fn pwd<F: FnOnce(Result<path::Path>)>(callback: F) {
 
 /// Alllocate some memory
 ///
-/// **Note:** This function is not implemented currently.
-pub fn malloc(_size: usize, _alignment: usize) -> Result<*mut core::ffi::c_void> {
-    todo!()
+/// On UNIX and Windows, the fake OS only gives out 256 KiB in total, unless
+/// you set the `NEOTRON_HEAP_SIZE` environment variable (like `64K` or `1M`).
+pub fn malloc(size: usize, alignment: usize) -> Result<*mut core::ffi::c_void> {
+    let api = get_api();
+    (api.malloc)(size, alignment).into()
 }
 
 /// Free some previously allocated memory.
 ///
-/// **Note:** This function is not implemented currently.
-pub fn free(_ptr: *mut core::ffi::c_void, _size: usize, _alignment: usize) {
-    todo!()
+/// You must pass the same `size` and `alignment` you gave to [`malloc`].
+pub fn free(ptr: *mut core::ffi::c_void, size: usize, alignment: usize) {
+    let api = get_api();
+    (api.free)(ptr, size, alignment)
 }
 
 /// Get a handle for Standard Input
@@ -392,10 +670,23 @@ pub const fn stderr() -> File {
     File(api::file::Handle::new_stderr())
 }
 
+/// Delay for some given duration before returning.
+///
+/// This is a busy-wait. It assumes the CPU runs at the clock speed given by
+/// the `NEOTRON_CPU_HZ` environment variable at build time (133 MHz if not
+/// set). If the CPU has a cycle counter, we use that, otherwise we time a
+/// loop using SysTick.
+#[cfg(all(target_os = "none", target_arch = "arm"))]
+pub fn delay(period: core::time::Duration) {
+    let hz = u64::from(cortex_m::CPU_HZ);
+    let cycles = (period.as_secs() * hz) + (u64::from(period.subsec_nanos()) * hz / 1_000_000_000);
+    cortex_m::delay_cycles(cycles);
+}
+
 /// Delay for some given duration before returning.
 ///
 /// Currently this does a badly calibrated nop busy-wait.
-#[cfg(target_os = "none")]
+#[cfg(all(target_os = "none", not(target_arch = "arm")))]
 pub fn delay(period: core::time::Duration) {
     // TODO: call OS sleep API?
     for _ in 0..period.as_micros() {
@@ -417,15 +708,23 @@ pub fn delay(period: core::time::Duration) {
 /// for you to press the appropriate key.
 pub fn wait_for_key() -> WaitForKey {
     use core::fmt::Write;
+    const TICK_PERIOD: core::time::Duration = core::time::Duration::from_millis(100);
     let mut ticker = "|/-\\".chars().cycle();
     let stdin = stdin();
     let mut stdout = stdout();
+    let mut last_tick: Option<time::Instant> = None;
+    let mut ticked = false;
     let result = loop {
-        let _ = write!(
-            stdout,
-            "\rPress Space for more, 'q' to quit... {}",
-            ticker.next().unwrap()
-        );
+        // If we can't measure time, the spinner just doesn't spin
+        if !ticked || last_tick.is_some_and(|t| t.elapsed() >= TICK_PERIOD) {
+            let _ = write!(
+                stdout,
+                "\rPress Space for more, 'q' to quit... {}",
+                ticker.next().unwrap()
+            );
+            last_tick = time::Instant::now();
+            ticked = true;
+        }
         let mut buffer = [0u8; 1];
         match stdin.read(&mut buffer) {
             Ok(0) => {
@@ -438,6 +737,9 @@ pub fn wait_for_key() -> WaitForKey {
                     break WaitForKey::Quit;
                 }
             }
+            Err(Error::EndOfFile) => {
+                break WaitForKey::Quit;
+            }
             Err(e) => {
                 let _ = writeln!(stdout, "Error {:?}", e);
                 break WaitForKey::Quit;
@@ -449,17 +751,88 @@ pub fn wait_for_key() -> WaitForKey {
 }
 
 /// Seed the 16-bit psuedorandom number generator
+///
+/// This seeds the global generator in the [`random`] module. See
+/// [`random::seed`] if you want to use a larger seed.
 pub fn srand(seed: u16) {
-    RAND_STATE.store(seed, core::sync::atomic::Ordering::Relaxed);
+    random::seed(u64::from(seed));
 }
 
 /// Get a 16-bit psuedorandom number
+///
+/// This takes the top 16 bits of a value from the global generator in the
+/// [`random`] module.
 pub fn rand() -> u16 {
-    let mut state = RAND_STATE.load(core::sync::atomic::Ordering::Relaxed);
-    let bit = (state ^ (state >> 2) ^ (state >> 3) ^ (state >> 5)) & 0x01;
-    state = (state >> 1) | (bit << 15);
-    RAND_STATE.store(state, core::sync::atomic::Ordering::Relaxed);
-    state
+    (random::next_u32() >> 16) as u16
+}
+
+/// Get the most stack the application has used so far, in bytes.
+///
+/// When the `stack-usage` feature is enabled, [`app_entry()`](app_entry)
+/// fills the unused stack with a known pattern before calling your
+/// application. This function looks for the deepest point where that pattern
+/// has been overwritten. Only the application's own stack region, between
+/// the top of the heap and the top of the TPA, is painted, so it can't see
+/// stack used below that - make the stack bigger with the
+/// `NEOTRON_STACK_SIZE` environment variable if you need more.
+///
+/// Returns `None` if the stack could not be painted (because the application
+/// isn't running on its own stack region), or on UNIX and Windows.
+#[cfg(feature = "stack-usage")]
+pub fn stack_usage() -> Option<usize> {
+    #[cfg(all(target_os = "none", target_arch = "arm"))]
+    return cortex_m::stack_usage();
+    #[cfg(not(all(target_os = "none", target_arch = "arm")))]
+    return None;
+}
+
+/// Print the stack usage to Standard Output.
+#[cfg(feature = "stack-usage-report")]
+fn report_stack_usage() {
+    if let Some(used) = stack_usage() {
+        let stdout = stdout();
+        let _ = stdout.write(b"Stack used: ");
+        write_decimal(&stdout, used as u32);
+        let _ = stdout.write(b" bytes\n");
+    }
+}
+
+/// Set a function to be called when the application panics.
+///
+/// The hook is called before the panic is reported, so you can use it to
+/// restore the terminal (e.g. with [`console::cursor_on`]) or flush a log
+/// file. Setting a new hook replaces any previous one.
+///
+/// The hook is removed when it runs, so if your hook panics it will not be
+/// called a second time.
+///
+/// On UNIX and Windows, the hook is run by a `std::panic` hook, before the
+/// default one.
+pub fn set_panic_hook(hook: PanicHook) {
+    PANIC_HOOK.store(hook as *mut (), Ordering::Relaxed);
+    #[cfg(not(target_os = "none"))]
+    {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            let default_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                run_panic_hook(info);
+                default_hook(info);
+            }));
+        });
+    }
+}
+
+/// Call the user's panic hook, if they set one.
+fn run_panic_hook(info: &PanicInfo) {
+    // Not a swap, as Armv6-M doesn't have atomic read-modify-write operations
+    let ptr = PANIC_HOOK.load(Ordering::Relaxed);
+    PANIC_HOOK.store(core::ptr::null_mut(), Ordering::Relaxed);
+    if !ptr.is_null() {
+        // Safety: we only ever store a `PanicHook` in `PANIC_HOOK`
+        let hook = unsafe { core::mem::transmute::<*mut (), PanicHook>(ptr) };
+        hook(info);
+    }
 }
 
 /// Get the API structure so we can call APIs manually.
@@ -474,7 +847,25 @@ fn get_api() -> &'static Api {
 ///
 /// If you are using this SDK on Windows or UNIX, your main function should
 /// call this function as the first thing it does. It will set up the SDK
-/// and then jump to `neotron_main()`.
+/// and then jump to `neotron_main()`. The [`entry!`] macro will write this
+/// function for you.
+///
+/// If Standard Input is a terminal, it is put into raw mode so your
+/// application gets each key as it is pressed. If it isn't (for example, when
+/// input is piped in from another program), the terminal is left alone and
+/// Standard Output and Standard Error are passed through unchanged, so your
+/// application can be driven from a shell pipeline or a script.
+///
+/// If the `NEOTRON_RECORD` environment variable is set, every OS call is
+/// recorded in the log file it names. If `NEOTRON_REPLAY` is set instead, the
+/// application is run against that log, rather than the fake OS. See
+/// [`backend::record`].
+///
+/// The fake OS only lets [`malloc`] give out 256 KiB, or as much as the
+/// `NEOTRON_HEAP_SIZE` environment variable says. If `NEOTRON_TPA_SIZE` is
+/// set (like `256K`), you get a warning when the application finishes if its
+/// static data and stack wouldn't have fitted in a Transient Program Area of
+/// that size.
 ///
 /// ```no_run
 /// #[cfg(not(target_os = "none"))]
@@ -491,10 +882,133 @@ fn get_api() -> &'static Api {
 /// ```
 #[cfg(not(target_os = "none"))]
 pub fn init() {
-    API.store(fake_os_api::get_ptr() as *mut Api, Ordering::Relaxed);
-    crossterm::terminal::enable_raw_mode().expect("enable raw mode");
-    let res = unsafe { neotron_main() };
-    crossterm::terminal::disable_raw_mode().expect("disable raw mode");
+    let res = if let Some(path) = std::env::var_os("NEOTRON_REPLAY") {
+        replay(&path)
+    } else {
+        fake_os_api::start();
+        match std::env::var_os("NEOTRON_RECORD") {
+            Some(path) => record(&path),
+            None => {
+                fake_os_api::enable_raw_mode();
+                install_host_os(fake_os_api::HostOs);
+                let res = fake_os_api::run_main(|| unsafe { neotron_main() });
+                fake_os_api::disable_raw_mode();
+                res
+            }
+        }
+    };
+    std::process::exit(res);
+}
+
+/// Run the application with the fake OS, recording every call to the log
+/// file at `path`.
+#[cfg(not(target_os = "none"))]
+fn record(path: &std::ffi::OsStr) -> i32 {
+    // The tracer goes inside the recorder, so the trace lines aren't recorded.
+    #[cfg(feature = "trace")]
+    let os = backend::trace::Tracer::new(fake_os_api::HostOs);
+    #[cfg(not(feature = "trace"))]
+    let os = fake_os_api::HostOs;
+    let recorder = backend::record::Recorder::new(os);
+    if let Err(e) = recorder.start(path) {
+        eprintln!("Failed to create recording {:?}: {}", path, e);
+        return 1;
+    }
+    fake_os_api::enable_raw_mode();
+    let recorder: &'static backend::record::Recorder<_> = Box::leak(Box::new(recorder));
+    // Safety: the application hasn't started yet, so nothing else is making
+    // OS calls.
+    unsafe { backend::install(recorder) };
+    let res = fake_os_api::run_main(|| unsafe { neotron_main() });
+    fake_os_api::disable_raw_mode();
+    recorder.stop();
+    res
+}
+
+/// Run the application against a recording made earlier, from the log file
+/// at `path`.
+#[cfg(not(target_os = "none"))]
+fn replay(path: &std::ffi::OsStr) -> i32 {
+    let replayer = match backend::record::Replayer::load(path) {
+        Ok(replayer) => replayer,
+        Err(e) => {
+            eprintln!("Failed to load recording {:?}: {}", path, e);
+            return 1;
+        }
+    };
+    // The tracer writes straight to the host, so it doesn't use up any
+    // records.
+    let replayer = install_host_os(replayer);
+    let res = fake_os_api::run_main(|| unsafe { neotron_main() });
+    let remaining = replayer.remaining();
+    if replayer.divergence().is_none() && remaining != 0 {
+        eprintln!(
+            "[replay] application finished with {} calls left in the log",
+            remaining
+        );
+    }
+    res
+}
+
+/// Make `os` the backend for the rest of the program, wrapped in a tracer if
+/// the `trace` feature is enabled.
+#[cfg(not(target_os = "none"))]
+fn install_host_os<B: backend::Backend>(os: B) -> &'static B {
+    #[cfg(feature = "trace")]
+    {
+        let os: &'static backend::trace::Tracer<B> =
+            Box::leak(Box::new(backend::trace::Tracer::new(os)));
+        // Safety: the application hasn't started yet, so nothing else is
+        // making OS calls.
+        unsafe { backend::install(os) };
+        os.inner()
+    }
+    #[cfg(not(feature = "trace"))]
+    {
+        let os: &'static B = Box::leak(Box::new(os));
+        // Safety: the application hasn't started yet, so nothing else is
+        // making OS calls.
+        unsafe { backend::install(os) };
+        os
+    }
+}
+
+/// Initialisation function for OSes other than Neotron OS, using your own
+/// [`Backend`](backend::Backend) instead of the built-in fake OS.
+///
+/// Call this instead of [`init`]. The terminal is left alone, and every OS
+/// call the application makes goes to `backend`.
+///
+/// ```no_run
+/// use neotron_sdk::backend::Backend;
+///
+/// /// Sends Standard Output to the host's stderr, and nothing else works.
+/// struct MyOs;
+///
+/// impl Backend for MyOs {
+///     fn write(&self, _fd: neotron_sdk::backend::file::Handle, buffer: &[u8]) -> neotron_sdk::Result<()> {
+///         eprint!("{}", String::from_utf8_lossy(buffer));
+///         Ok(())
+///     }
+/// }
+///
+/// #[cfg(not(target_os = "none"))]
+/// fn main() {
+///     neotron_sdk::init_with_backend(&MyOs);
+/// }
+///
+/// #[no_mangle]
+/// extern "C" fn neotron_main() -> i32 {
+///     // Your code here
+///     0
+/// }
+/// ```
+#[cfg(not(target_os = "none"))]
+pub fn init_with_backend<B: backend::Backend>(backend: &'static B) {
+    // Safety: the application hasn't started yet, so nothing else is making
+    // OS calls.
+    unsafe { backend::install(backend) };
+    let res = fake_os_api::run_main(|| unsafe { neotron_main() });
     std::process::exit(res);
 }
 
@@ -503,19 +1017,76 @@ pub fn init() {
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use core::fmt::Write;
+    run_panic_hook(info);
     let stdout = stdout();
     let _ = writeln!(&stdout, "Panic:\n{:#?}", info);
-    loop {}
+    abort_main(PANIC_EXIT_CODE)
+}
+
+#[cfg(all(
+    target_os = "none",
+    feature = "location-panic",
+    not(feature = "fancy-panic")
+))]
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    run_panic_hook(info);
+    let stdout = stdout();
+    let _ = stdout.write(b"Panic!");
+    if let Some(location) = info.location() {
+        let _ = stdout.write(b" at ");
+        let _ = stdout.write(location.file().as_bytes());
+        let _ = stdout.write(b":");
+        write_decimal(&stdout, location.line());
+        let _ = stdout.write(b":");
+        write_decimal(&stdout, location.column());
+    }
+    let _ = stdout.write(b"\n");
+    if let Some(message) = info.message().as_str() {
+        let _ = stdout.write(message.as_bytes());
+        let _ = stdout.write(b"\n");
+    }
+    abort_main(PANIC_EXIT_CODE)
 }
 
-#[cfg(all(target_os = "none", not(feature = "fancy-panic")))]
+/// Write an integer to a file in decimal, without using `core::fmt`.
+#[cfg(any(
+    all(
+        target_os = "none",
+        feature = "location-panic",
+        not(feature = "fancy-panic")
+    ),
+    feature = "stack-usage-report"
+))]
+fn write_decimal(f: &File, mut value: u32) {
+    // u32::MAX is 10 digits long
+    let mut buffer = [0u8; 10];
+    let mut idx = buffer.len();
+    loop {
+        idx -= 1;
+        buffer[idx] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    let _ = f.write(&buffer[idx..]);
+}
+
+#[cfg(all(
+    target_os = "none",
+    not(feature = "fancy-panic"),
+    not(feature = "location-panic")
+))]
 #[inline(never)]
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     use core::fmt::Write;
+    run_panic_hook(info);
     let stdout = stdout();
     let _ = writeln!(&stdout, "Panic!");
-    loop {}
+    abort_main(PANIC_EXIT_CODE)
 }
 
 // ============================================================================
@@ -0,0 +1,542 @@
+//! Path related types
+//!
+//! Re-exports the borrowed [`Path`] type from the Neotron API, and adds an
+//! owned, fixed-capacity [`PathBuf`] you can build paths in, and MS-DOS style
+//! wildcard matching with [`glob_match`].
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use crate::{Error, Result, MAX_PATH_LEN};
+
+pub use neotron_api::path::Path;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// An owned path to a file or directory.
+///
+/// Holds up to [`MAX_PATH_LEN`] bytes, with no heap allocation. Anything which
+/// would make the path longer than that returns [`Error::InvalidPath`] and
+/// leaves the path unchanged.
+///
+/// Paths are kept in a normalised form: `.` components are removed, `..`
+/// components remove the component before them, and there are no repeated or
+/// trailing `/` characters (except for the root directory of a drive, like
+/// `HD0:/`). You cannot `..` your way above the root of a drive, but a
+/// relative path may start with `..`.
+///
+/// ```
+/// use neotron_sdk::path::PathBuf;
+/// let mut path: PathBuf = "HD0:/DOCS".parse().unwrap();
+/// path.push("./2024/../SALES.TXT").unwrap();
+/// assert_eq!(path.as_str(), "HD0:/DOCS/SALES.TXT");
+/// assert_eq!(path.extension(), Some("TXT"));
+/// assert_eq!(path.parent(), Some("HD0:/DOCS"));
+/// ```
+#[derive(Clone)]
+pub struct PathBuf {
+    buffer: [u8; MAX_PATH_LEN],
+    len: usize,
+}
+
+impl PathBuf {
+    /// Create a new, empty, path.
+    pub const fn new() -> PathBuf {
+        PathBuf {
+            buffer: [0u8; MAX_PATH_LEN],
+            len: 0,
+        }
+    }
+
+    /// View this path as a string-slice.
+    pub fn as_str(&self) -> &str {
+        // Safety: we only ever copy in whole UTF-8 strings, and only ever
+        // truncate at an ASCII `/`.
+        unsafe { core::str::from_utf8_unchecked(&self.buffer[0..self.len]) }
+    }
+
+    /// Get this path as a borrowed [`Path`], so you can pass it to functions
+    /// like [`File::open`](crate::File::open).
+    ///
+    /// Returns [`Error::InvalidPath`] if the path is empty.
+    pub fn as_path(&self) -> Result<Path<'_>> {
+        Path::new(self.as_str())
+    }
+
+    /// Is this path empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Make this path empty.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Add a path on to the end of this one.
+    ///
+    /// If `path` is absolute (i.e. it has a drive specifier), it replaces this
+    /// path entirely. Otherwise each of its components are added, with `.` and
+    /// `..` components resolved as they go.
+    pub fn push(&mut self, path: &str) -> Result<()> {
+        // Checks for control characters and misplaced `:` and `/`
+        Path::new(path)?;
+        let mut new_path = self.clone();
+        let relative_part = match path.split_once(Path::DRIVE_SEP) {
+            Some((drive, rest)) => {
+                new_path.clear();
+                new_path.append(drive)?;
+                new_path.append(":/")?;
+                rest
+            }
+            None => path,
+        };
+        for component in relative_part.split(Path::PATH_SEP) {
+            new_path.push_component(component)?;
+        }
+        *self = new_path;
+        Ok(())
+    }
+
+    /// Create a new path by adding a path on to the end of this one.
+    ///
+    /// See [`PathBuf::push`].
+    pub fn join(&self, path: &str) -> Result<PathBuf> {
+        let mut new_path = self.clone();
+        new_path.push(path)?;
+        Ok(new_path)
+    }
+
+    /// Remove the last component of this path.
+    ///
+    /// Returns `false` (and does nothing) if there is nothing to remove,
+    /// because the path is empty or is the root directory of a drive.
+    pub fn pop(&mut self) -> bool {
+        match self.parent() {
+            Some(parent) => {
+                self.len = parent.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the path of the directory this path is in.
+    ///
+    /// * `HD0:/DOCS/SALES.TXT` has a parent of `HD0:/DOCS`.
+    /// * `HD0:/DOCS` has a parent of `HD0:/`.
+    /// * `HD0:/` has no parent.
+    /// * `SALES.TXT` has a parent of `` (the empty path).
+    /// * `..` also has a parent of `` - we don't look at what `..` refers to.
+    pub fn parent(&self) -> Option<&str> {
+        let path = self.as_str();
+        let root_len = self.root_len();
+        if path.len() == root_len {
+            // Empty, or the root of a drive
+            return None;
+        }
+        match path.rfind(Path::PATH_SEP) {
+            Some(idx) if idx < root_len => Some(&path[0..root_len]),
+            Some(idx) => Some(&path[0..idx]),
+            None => Some(""),
+        }
+    }
+
+    /// Get the last component of this path, which might be a file or a
+    /// directory.
+    ///
+    /// Returns `None` for an empty path, the root of a drive, or a path
+    /// ending in `..`.
+    pub fn file_name(&self) -> Option<&str> {
+        let path = self.as_str();
+        let root_len = self.root_len();
+        if path.len() == root_len {
+            return None;
+        }
+        let name = match path.rfind(Path::PATH_SEP) {
+            Some(idx) if idx >= root_len => &path[idx + 1..],
+            _ => &path[root_len..],
+        };
+        if name == ".." {
+            None
+        } else {
+            Some(name)
+        }
+    }
+
+    /// Get the [`file_name`](PathBuf::file_name) without its extension.
+    pub fn file_stem(&self) -> Option<&str> {
+        let name = self.file_name()?;
+        match name.rsplit_once('.') {
+            Some((stem, _extension)) if !stem.is_empty() => Some(stem),
+            _ => Some(name),
+        }
+    }
+
+    /// Get the extension of the [`file_name`](PathBuf::file_name), without
+    /// the `.`.
+    pub fn extension(&self) -> Option<&str> {
+        let name = self.file_name()?;
+        match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => Some(extension),
+            _ => None,
+        }
+    }
+
+    /// Get the drive specifier for this path, if it has one.
+    ///
+    /// A path like `HD0:/DOCS/SALES.TXT` has a drive specifier of `HD0`.
+    pub fn drive(&self) -> Option<&str> {
+        self.as_str()
+            .split_once(Path::DRIVE_SEP)
+            .map(|(drive, _rest)| drive)
+    }
+
+    /// Is this an absolute path?
+    ///
+    /// Absolute paths have drive specifiers. Relative paths do not.
+    pub fn is_absolute(&self) -> bool {
+        self.drive().is_some()
+    }
+
+    /// How long is the part of the path we never remove - the drive specifier
+    /// and the root `/`?
+    fn root_len(&self) -> usize {
+        match self.as_str().find(Path::DRIVE_SEP) {
+            // Skip the `:` and the `/`
+            Some(idx) => idx + 2,
+            None => 0,
+        }
+    }
+
+    /// Add one component to the end of the path, handling `.` and `..`.
+    fn push_component(&mut self, component: &str) -> Result<()> {
+        match component {
+            "" | "." => Ok(()),
+            ".." => {
+                if self.file_name().is_some() {
+                    self.pop();
+                    Ok(())
+                } else if self.is_absolute() {
+                    // Can't go above the root
+                    Ok(())
+                } else {
+                    self.append_component("..")
+                }
+            }
+            _ => self.append_component(component),
+        }
+    }
+
+    /// Add a component to the end of the path, with a `/` if required.
+    fn append_component(&mut self, component: &str) -> Result<()> {
+        if !self.is_empty() && !self.as_str().ends_with(Path::PATH_SEP) {
+            self.append("/")?;
+        }
+        self.append(component)
+    }
+
+    /// Copy a string on to the end of the path.
+    fn append(&mut self, s: &str) -> Result<()> {
+        let new_len = self.len + s.len();
+        if new_len > MAX_PATH_LEN {
+            return Err(Error::InvalidPath);
+        }
+        self.buffer[self.len..new_len].copy_from_slice(s.as_bytes());
+        self.len = new_len;
+        Ok(())
+    }
+}
+
+impl Default for PathBuf {
+    fn default() -> PathBuf {
+        PathBuf::new()
+    }
+}
+
+impl core::str::FromStr for PathBuf {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PathBuf> {
+        let mut path = PathBuf::new();
+        path.push(s)?;
+        Ok(path)
+    }
+}
+
+impl TryFrom<&str> for PathBuf {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<PathBuf> {
+        s.parse()
+    }
+}
+
+impl TryFrom<&Path<'_>> for PathBuf {
+    type Error = Error;
+
+    fn try_from(path: &Path<'_>) -> Result<PathBuf> {
+        path.as_str().parse()
+    }
+}
+
+impl PartialEq for PathBuf {
+    fn eq(&self, other: &PathBuf) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for PathBuf {}
+
+impl core::fmt::Debug for PathBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PathBuf({:?})", self.as_str())
+    }
+}
+
+impl core::fmt::Display for PathBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Does an 8.3 filename match an MS-DOS style wildcard pattern?
+///
+/// The match is case-insensitive. The name and extension are matched
+/// separately, as MS-DOS does:
+///
+/// * `?` matches any one character, or nothing at the end of the name or
+///   extension - so `DATA??.BIN` matches `DATA01.BIN` and `DATA.BIN`.
+/// * `*` matches everything up to the end of the name or extension. Anything
+///   after a `*` (before the `.`) is ignored.
+/// * A pattern with no `.` only matches files with no extension, unless the
+///   pattern ends with `*` - so `*` and `READ*` match files with any
+///   extension, but `README` only matches `README`.
+///
+/// Names which don't fit in 8.3 format never match (except that `.` and `..`
+/// match a pattern which is exactly the same). Patterns which don't fit are
+/// cut short to 8.3, as MS-DOS does, so `LONGFILENAME.TXT` matches
+/// `LONGFILE.TXT`.
+///
+/// ```
+/// use neotron_sdk::path::glob_match;
+/// assert!(glob_match("*.TXT", "readme.txt"));
+/// assert!(glob_match("DATA??.BIN", "DATA7.BIN"));
+/// assert!(!glob_match("*.TXT", "README"));
+/// assert!(glob_match("*", "README.TXT"));
+/// ```
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    if name == "." || name == ".." {
+        return pattern == name;
+    }
+    let Some(name) = to_fcb_name(name) else {
+        return false;
+    };
+    let pattern = to_fcb_pattern(pattern);
+    pattern
+        .iter()
+        .zip(name.iter())
+        .all(|(p, n)| *p == b'?' || p.eq_ignore_ascii_case(n))
+}
+
+/// Convert an 8.3 filename to the 11 character space-padded format used in
+/// MS-DOS File Control Blocks.
+///
+/// Returns `None` if the name won't fit.
+fn to_fcb_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut result = [b' '; 11];
+    result[0..base.len()].copy_from_slice(base.as_bytes());
+    result[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(result)
+}
+
+/// Convert a wildcard pattern to the 11 character format used in MS-DOS File
+/// Control Blocks, with each `*` expanded into `?` characters.
+fn to_fcb_pattern(pattern: &str) -> [u8; 11] {
+    let (base, extension) = match pattern.rsplit_once('.') {
+        Some((base, extension)) => (base, extension),
+        None if pattern.ends_with('*') => (pattern, "*"),
+        None => (pattern, ""),
+    };
+    let mut result = [b' '; 11];
+    fill_fcb_field(&mut result[0..8], base);
+    fill_fcb_field(&mut result[8..11], extension);
+    result
+}
+
+/// Fill one field of an FCB-style pattern, expanding `*`.
+///
+/// Anything which doesn't fit is ignored.
+fn fill_fcb_field(field: &mut [u8], pattern: &str) {
+    for (slot, ch) in field.iter_mut().zip(pattern.bytes()) {
+        if ch == b'*' {
+            break;
+        }
+        *slot = ch;
+    }
+    if let Some(star) = pattern.bytes().position(|b| b == b'*') {
+        for slot in field.iter_mut().skip(star) {
+            *slot = b'?';
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(s: &str) -> PathBuf {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn dots_are_resolved() {
+        assert_eq!(path("HD0:/A/./B/../C").as_str(), "HD0:/A/C");
+        assert_eq!(path("./A/.").as_str(), "A");
+        assert_eq!(path("A/..").as_str(), "");
+    }
+
+    #[test]
+    fn repeated_and_trailing_slashes_are_removed() {
+        assert_eq!(path("HD0://A//B/").as_str(), "HD0:/A/B");
+        assert_eq!(path("HD0:/").as_str(), "HD0:/");
+        assert_eq!(path("A//B/").as_str(), "A/B");
+    }
+
+    #[test]
+    fn cannot_go_above_the_root() {
+        assert_eq!(path("HD0:/../A").as_str(), "HD0:/A");
+        assert_eq!(path("HD0:/A/../..").as_str(), "HD0:/");
+    }
+
+    #[test]
+    fn relative_paths_keep_leading_dot_dot() {
+        assert_eq!(path("../../A").as_str(), "../../A");
+        assert_eq!(path("A/../..").as_str(), "..");
+        assert_eq!(path("../A/..").as_str(), "..");
+    }
+
+    #[test]
+    fn parent() {
+        assert_eq!(path("HD0:/DOCS/SALES.TXT").parent(), Some("HD0:/DOCS"));
+        assert_eq!(path("HD0:/DOCS").parent(), Some("HD0:/"));
+        assert_eq!(path("HD0:/").parent(), None);
+        assert_eq!(path("SALES.TXT").parent(), Some(""));
+        assert_eq!(path("..").parent(), Some(""));
+        assert_eq!(PathBuf::new().parent(), None);
+    }
+
+    #[test]
+    fn pop_stops_at_the_root() {
+        let mut p = path("HD0:/A/B");
+        assert!(p.pop());
+        assert_eq!(p.as_str(), "HD0:/A");
+        assert!(p.pop());
+        assert_eq!(p.as_str(), "HD0:/");
+        assert!(!p.pop());
+        assert_eq!(p.as_str(), "HD0:/");
+    }
+
+    #[test]
+    fn absolute_push_replaces_the_path() {
+        let mut p = path("HD0:/A");
+        p.push("HD1:/B").unwrap();
+        assert_eq!(p.as_str(), "HD1:/B");
+    }
+
+    #[test]
+    fn names_and_extensions() {
+        let p = path("HD0:/DOCS/SALES.TXT");
+        assert_eq!(p.file_name(), Some("SALES.TXT"));
+        assert_eq!(p.file_stem(), Some("SALES"));
+        assert_eq!(p.extension(), Some("TXT"));
+        assert_eq!(p.drive(), Some("HD0"));
+        assert!(p.is_absolute());
+        let p = path(".PROFILE");
+        assert_eq!(p.file_stem(), Some(".PROFILE"));
+        assert_eq!(p.extension(), None);
+        assert_eq!(path("..").file_name(), None);
+        assert_eq!(path("HD0:/").file_name(), None);
+        assert!(!path("A").is_absolute());
+    }
+
+    #[test]
+    fn too_long_leaves_the_path_unchanged() {
+        let mut p = path("HD0:/A");
+        let long = "B".repeat(MAX_PATH_LEN);
+        assert_eq!(p.push(&long), Err(Error::InvalidPath));
+        assert_eq!(p.as_str(), "HD0:/A");
+        let exact = "C".repeat(MAX_PATH_LEN - "HD0:/A/".len());
+        p.push(&exact).unwrap();
+        assert_eq!(p.as_str().len(), MAX_PATH_LEN);
+    }
+
+    #[test]
+    fn glob_is_case_insensitive() {
+        assert!(glob_match("*.TXT", "readme.txt"));
+        assert!(glob_match("read*.txt", "README.TXT"));
+        assert!(!glob_match("*.TXT", "README.BIN"));
+    }
+
+    #[test]
+    fn glob_question_mark_matches_one_or_nothing() {
+        assert!(glob_match("DATA??.BIN", "DATA01.BIN"));
+        assert!(glob_match("DATA??.BIN", "DATA7.BIN"));
+        assert!(glob_match("DATA??.BIN", "DATA.BIN"));
+        assert!(!glob_match("DATA??.BIN", "DATA123.BIN"));
+        assert!(glob_match("A.?", "A"));
+        assert!(!glob_match("?.TXT", ".TXT"));
+    }
+
+    #[test]
+    fn glob_ignores_everything_after_a_star() {
+        assert!(glob_match("A*Z.TXT", "ABC.TXT"));
+        assert!(glob_match("*.T*X", "A.TXT"));
+    }
+
+    #[test]
+    fn glob_without_a_dot_needs_no_extension() {
+        assert!(glob_match("README", "README"));
+        assert!(!glob_match("README", "README.TXT"));
+        assert!(glob_match("READ*", "README.TXT"));
+        assert!(glob_match("*", "README.TXT"));
+        assert!(glob_match("*", "README"));
+        assert!(glob_match("*.*", "README"));
+    }
+
+    #[test]
+    fn glob_only_matches_8_3_names() {
+        assert!(!glob_match("*", "LONGFILENAME.TXT"));
+        assert!(!glob_match("*.*", "README.TEXT"));
+        assert!(!glob_match("*", ".PROFILE"));
+        assert!(glob_match("LONGFILENAME.TXT", "LONGFILE.TXT"));
+        assert!(glob_match("*.TEXT", "README.TEX"));
+    }
+
+    #[test]
+    fn glob_dot_entries_only_match_themselves() {
+        assert!(glob_match(".", "."));
+        assert!(glob_match("..", ".."));
+        assert!(!glob_match("*", "."));
+        assert!(!glob_match("*.*", ".."));
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
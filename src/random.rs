@@ -0,0 +1,443 @@
+//! Pseudo-random number generation
+//!
+//! Provides [`Rng`], a small and fast 32-bit pseudo-random number generator
+//! using the [PCG](https://www.pcg-random.org) algorithm (PCG-XSH-RR with 64
+//! bits of state). It is not cryptographically secure.
+//!
+//! Use [`seed_from_entropy`] if you want different numbers each time your
+//! application runs.
+//!
+//! If you enable the `rand_core` feature, [`Rng`] implements
+//! `rand_core::RngCore` and `rand_core::SeedableRng`, so you can use it with
+//! the `rand` crate.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The PCG multiplier for 64-bit state.
+const MULTIPLIER: u64 = 6364136223846793005;
+
+/// The PCG increment. We only support one stream, so this is fixed.
+const INCREMENT: u64 = 1442695040888963407;
+
+// ============================================================================
+// Static Variables
+// ============================================================================
+
+/// Upper 32 bits of the global generator state.
+///
+/// Starts off as if seeded with zero. We use two 32-bit atomics because
+/// Armv6-M doesn't have 64-bit atomics.
+static GLOBAL_STATE_HI: AtomicU32 = AtomicU32::new((Rng::new(0).state >> 32) as u32);
+
+/// Lower 32 bits of the global generator state.
+static GLOBAL_STATE_LO: AtomicU32 = AtomicU32::new(Rng::new(0).state as u32);
+
+/// Some RAM that isn't initialised when the application is loaded.
+///
+/// Whatever was left there (e.g. by the previous application) is a source of
+/// entropy. Only ever read with volatile reads.
+#[cfg(target_os = "none")]
+#[link_section = ".uninit.neotron_sdk.ENTROPY_RAM"]
+static mut ENTROPY_RAM: core::mem::MaybeUninit<[u32; 16]> = core::mem::MaybeUninit::uninit();
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A 32-bit pseudo-random number generator.
+///
+/// Any seed is fine, including zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a new generator from a seed.
+    pub const fn new(seed: u64) -> Rng {
+        // This is the PCG reference seeding procedure
+        let state = 0u64.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT);
+        let state = state.wrapping_add(seed);
+        let state = state.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT);
+        Rng { state }
+    }
+
+    /// Get the next 32-bit pseudo-random number.
+    pub fn next_u32(&mut self) -> u32 {
+        let old_state = self.state;
+        self.state = old_state.wrapping_mul(MULTIPLIER).wrapping_add(INCREMENT);
+        let xorshifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
+        let rot = (old_state >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Get the next 64-bit pseudo-random number.
+    pub fn next_u64(&mut self) -> u64 {
+        let hi = self.next_u32() as u64;
+        let lo = self.next_u32() as u64;
+        (hi << 32) | lo
+    }
+
+    /// Fill a buffer with pseudo-random bytes.
+    pub fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(4) {
+            let value = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&value[0..chunk.len()]);
+        }
+    }
+
+    /// Get a pseudo-random number in the given range.
+    ///
+    /// Every value in the range is equally likely.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is empty.
+    pub fn gen_range(&mut self, range: core::ops::Range<u32>) -> u32 {
+        assert!(range.start < range.end, "empty range");
+        let span = range.end - range.start;
+        // Reject values from the incomplete final 'bucket', so we don't bias
+        // towards the low end of the range.
+        let limit = u32::MAX - (u32::MAX % span);
+        loop {
+            let value = self.next_u32();
+            if value < limit {
+                return range.start + (value % span);
+            }
+        }
+    }
+
+    /// Put the items in a slice into a pseudo-random order.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        // Fisher-Yates shuffle
+        for i in (1..items.len()).rev() {
+            let j = self.gen_range(0..(i as u32 + 1));
+            items.swap(i, j as usize);
+        }
+    }
+
+    /// Get a copy of the global generator, as used by [`rand()`](crate::rand).
+    pub fn global() -> Rng {
+        let hi = GLOBAL_STATE_HI.load(Ordering::Relaxed) as u64;
+        let lo = GLOBAL_STATE_LO.load(Ordering::Relaxed) as u64;
+        Rng {
+            state: (hi << 32) | lo,
+        }
+    }
+
+    /// Replace the global generator, as used by [`rand()`](crate::rand).
+    pub fn set_global(rng: &Rng) {
+        GLOBAL_STATE_HI.store((rng.state >> 32) as u32, Ordering::Relaxed);
+        GLOBAL_STATE_LO.store(rng.state as u32, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        Rng::next_u32(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        Rng::next_u64(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Rng::fill_bytes(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        Rng::fill_bytes(self, dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::SeedableRng for Rng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Rng {
+        Rng::new(u64::from_le_bytes(seed))
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Get a 32-bit pseudo-random number from the global generator.
+pub fn next_u32() -> u32 {
+    let mut rng = Rng::global();
+    let value = rng.next_u32();
+    Rng::set_global(&rng);
+    value
+}
+
+/// Get a pseudo-random number in the given range, from the global generator.
+///
+/// See [`Rng::gen_range`].
+pub fn gen_range(range: core::ops::Range<u32>) -> u32 {
+    let mut rng = Rng::global();
+    let value = rng.gen_range(range);
+    Rng::set_global(&rng);
+    value
+}
+
+/// Put the items in a slice into a pseudo-random order, using the global
+/// generator.
+///
+/// See [`Rng::shuffle`].
+pub fn shuffle<T>(items: &mut [T]) {
+    let mut rng = Rng::global();
+    rng.shuffle(items);
+    Rng::set_global(&rng);
+}
+
+/// Seed the global generator.
+pub fn seed(seed: u64) {
+    Rng::set_global(&Rng::new(seed));
+}
+
+/// Seed the global generator with a value that is different every time.
+///
+/// This mixes together:
+///
+/// * The time between each of the next `keypresses` keys typed on Standard
+///   Input. Pass `0` if you don't want to wait for the user.
+/// * On Neotron OS, the contents of some uninitialised RAM.
+/// * On UNIX and Windows, the current time.
+///
+/// The seed is returned, in case you want to show it to the user so they can
+/// repeat the same sequence later with [`seed`].
+pub fn seed_from_entropy(keypresses: usize) -> u64 {
+    let mut pool = 0;
+    for value in uninit_ram_entropy() {
+        pool = mix(pool, value.into());
+    }
+    pool = mix(pool, clock_entropy());
+    let stdin = crate::stdin();
+    let mut counter: u64 = 0;
+    let mut remaining = keypresses;
+    while remaining > 0 {
+        let mut buffer = [0u8; 1];
+        match stdin.read(&mut buffer) {
+            Ok(0) => {
+                counter = counter.wrapping_add(1);
+            }
+            Ok(_) => {
+                pool = mix(pool, counter ^ u64::from(buffer[0]));
+                remaining -= 1;
+            }
+            Err(_) => {
+                break;
+            }
+        }
+    }
+    seed(pool);
+    pool
+}
+
+/// Stir a value into an entropy pool, using the SplitMix64 finaliser.
+fn mix(pool: u64, value: u64) -> u64 {
+    let mut z = (pool ^ value).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Read the uninitialised RAM we reserved for entropy.
+#[cfg(target_os = "none")]
+fn uninit_ram_entropy() -> impl Iterator<Item = u32> {
+    let start = core::ptr::addr_of!(ENTROPY_RAM) as *const u32;
+    // Safety: the memory is ours, and we use volatile reads because it has
+    // never been written to.
+    (0..16).map(move |idx| unsafe { start.add(idx).read_volatile() })
+}
+
+/// Read the uninitialised RAM we reserved for entropy.
+///
+/// UNIX and Windows zero all static variables, so there isn't any.
+#[cfg(not(target_os = "none"))]
+fn uninit_ram_entropy() -> impl Iterator<Item = u32> {
+    core::iter::empty()
+}
+
+/// Get some entropy from the system clock.
+///
+/// Neotron OS doesn't give us a clock, so there isn't any.
+#[cfg(target_os = "none")]
+fn clock_entropy() -> u64 {
+    0
+}
+
+/// Get some entropy from the system clock.
+#[cfg(not(target_os = "none"))]
+fn clock_entropy() -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() ^ (u64::from(now.subsec_nanos()) << 32) ^ u64::from(std::process::id())
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Stops two tests using the global generator at the same time.
+    static GLOBAL_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_global() -> MutexGuard<'static, ()> {
+        GLOBAL_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn fixed_seed_gives_fixed_sequence() {
+        let mut rng = Rng::new(42);
+        let values: [u32; 4] = core::array::from_fn(|_| rng.next_u32());
+        assert_eq!(values, [0xc2f5_7bd6, 0x6b07_c4a9, 0x72b7_b29b, 0x4421_5383]);
+        let mut rng = Rng::new(42);
+        assert_eq!(rng.next_u64(), 0xc2f5_7bd6_6b07_c4a9);
+    }
+
+    #[test]
+    fn seed_zero_does_not_get_stuck() {
+        let mut rng = Rng::new(0);
+        let values: [u32; 4] = core::array::from_fn(|_| rng.next_u32());
+        assert_eq!(values, [0xe823_a24e, 0x7a7e_cbd9, 0x89fd_6c06, 0xae64_6aa8]);
+        let mut rng = Rng::new(0);
+        let first = rng.next_u32();
+        assert!((0..100).any(|_| rng.next_u32() != first));
+    }
+
+    #[test]
+    fn fill_bytes_handles_partial_words() {
+        let mut buffer = [0u8; 7];
+        Rng::new(42).fill_bytes(&mut buffer);
+        assert_eq!(buffer, [0xd6, 0x7b, 0xf5, 0xc2, 0xa9, 0xc4, 0x07]);
+    }
+
+    #[test]
+    fn gen_range_stays_in_bounds() {
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            let value = rng.gen_range(10..20);
+            assert!((10..20).contains(&value));
+        }
+        for n in [0, 7, u32::MAX - 1] {
+            assert_eq!(rng.gen_range(n..n + 1), n);
+        }
+        for _ in 0..1000 {
+            assert!(rng.gen_range(0..u32::MAX) < u32::MAX);
+        }
+    }
+
+    #[test]
+    fn gen_range_covers_the_range() {
+        let mut rng = Rng::new(2);
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            seen[rng.gen_range(0..6) as usize] = true;
+        }
+        assert!(seen.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    #[should_panic(expected = "empty range")]
+    fn gen_range_panics_on_empty_range() {
+        Rng::new(0).gen_range(5..5);
+    }
+
+    #[test]
+    fn shuffle_gives_a_permutation() {
+        let mut rng = Rng::new(3);
+        let mut items: Vec<u32> = (0..50).collect();
+        rng.shuffle(&mut items);
+        assert_ne!(items, (0..50).collect::<Vec<u32>>());
+        items.sort();
+        assert_eq!(items, (0..50).collect::<Vec<u32>>());
+        let mut empty: [u32; 0] = [];
+        rng.shuffle(&mut empty);
+    }
+
+    #[test]
+    fn global_generator_round_trips() {
+        let _lock = lock_global();
+        let mut rng = Rng::new(99);
+        rng.next_u32();
+        Rng::set_global(&rng);
+        assert_eq!(Rng::global(), rng);
+        assert_eq!(next_u32(), rng.next_u32());
+        assert_eq!(Rng::global(), rng);
+        seed(99);
+        assert_eq!(Rng::global(), Rng::new(99));
+    }
+
+    #[test]
+    fn rand_is_the_top_of_the_global_generator() {
+        let _lock = lock_global();
+        crate::srand(0);
+        let mut rng = Rng::new(0);
+        let values: Vec<u16> = (0..4).map(|_| crate::rand()).collect();
+        let expected: Vec<u16> = (0..4).map(|_| (rng.next_u32() >> 16) as u16).collect();
+        assert_eq!(values, expected);
+        assert_eq!(values[0], 0xe823);
+    }
+
+    /// Seed from entropy under a test OS, returning what was left on
+    /// Standard Input afterwards.
+    fn seed_with_stdin(os: crate::testing::TestOs, keypresses: usize) -> Vec<u8> {
+        let _lock = lock_global();
+        let mut left = Vec::new();
+        let output = os.run(|| {
+            seed_from_entropy(keypresses);
+            let mut buffer = [0u8; 8];
+            loop {
+                match crate::stdin().read(&mut buffer) {
+                    Ok(n) => left.extend_from_slice(&buffer[0..n]),
+                    Err(crate::Error::EndOfFile) => break Ok::<(), crate::Error>(()),
+                    Err(e) => break Err(e),
+                }
+            }
+        });
+        assert_eq!(output.exit_code, 0);
+        left
+    }
+
+    #[test]
+    fn seed_from_entropy_without_keypresses_leaves_stdin_alone() {
+        let os = crate::testing::TestOs::new().stdin(b"abc");
+        assert_eq!(seed_with_stdin(os, 0), b"abc");
+    }
+
+    #[test]
+    fn seed_from_entropy_reads_exactly_the_keypresses() {
+        let os = crate::testing::TestOs::new()
+            .stdin(b"a")
+            .delay(std::time::Duration::from_millis(5))
+            .stdin(b"bcd");
+        assert_eq!(seed_with_stdin(os, 2), b"cd");
+    }
+
+    #[test]
+    fn seed_from_entropy_stops_at_end_of_file() {
+        let os = crate::testing::TestOs::new().stdin(b"a");
+        assert_eq!(seed_with_stdin(os, 3), b"");
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
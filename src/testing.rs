@@ -0,0 +1,822 @@
+//! Testing applications on UNIX and Windows
+//!
+//! Lets you run an application function inside your test suite, without a
+//! terminal. The application talks to a scripted OS instead of the real one:
+//!
+//! * Standard Input is fed from a script of bytes, with optional pauses.
+//! * Standard Output and Standard Error are captured.
+//! * Files and directories live in an in-memory filesystem.
+//! * Optionally, some calls fail (see [`TestOs::faults`]).
+//!
+//! When the application returns, you get its exit code, everything it wrote,
+//! and the final state of the filesystem, so you can compare them against the
+//! expected ("golden") output.
+//!
+//! ```
+//! use neotron_sdk::testing::TestOs;
+//!
+//! let output = TestOs::new()
+//!     .stdin(b"hello")
+//!     .file("HD0:/README.TXT", b"Read me")
+//!     .run(|| {
+//!         let mut buffer = [0u8; 16];
+//!         let n = neotron_sdk::stdin().read(&mut buffer)?;
+//!         neotron_sdk::stdout().write(&buffer[0..n])?;
+//!         neotron_sdk::stderr().write(b"done\n")?;
+//!         Ok::<(), neotron_sdk::Error>(())
+//!     });
+//! assert_eq!(output.exit_code, 0);
+//! assert_eq!(output.stdout_str(), "hello");
+//! assert_eq!(output.stderr_str(), "done\n");
+//! ```
+//!
+//! Only one application can run at a time, because the SDK keeps the OS API
+//! in a global variable. [`TestOs::run`] takes a lock, so tests running in
+//! parallel threads wait for each other.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{
+    backend::{
+        self, dir,
+        fault::{FaultInjector, Faults},
+        file, Backend,
+    },
+    path::PathBuf,
+    Error, Result, Termination, PANIC_EXIT_CODE,
+};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The first file handle we give out, after stdin, stdout and stderr.
+const FIRST_FILE_HANDLE: usize = 3;
+
+// ============================================================================
+// Static Variables
+// ============================================================================
+
+/// Stops two tests running an application at the same time.
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// The OS being used by the application currently running.
+static CURRENT: Mutex<Option<TestOs>> = Mutex::new(None);
+
+/// The backend we install, which injects faults and then calls [`CURRENT`].
+static TEST_BACKEND: FaultInjector<CurrentOs> = FaultInjector::new(CurrentOs);
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A scripted OS for running an application in a test.
+///
+/// Build one up with the builder methods, then call [`TestOs::run`].
+#[derive(Debug, Default)]
+pub struct TestOs {
+    stdin: VecDeque<Input>,
+    /// When the current [`Input::Delay`] ends, if we've started one.
+    delay_until: Option<Instant>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    fs: MemoryFs,
+    open_files: Vec<Option<OpenFile>>,
+    open_dirs: Vec<Option<OpenDir>>,
+    faults: Faults,
+}
+
+impl TestOs {
+    /// Create a new scripted OS, with nothing on Standard Input and an empty
+    /// filesystem.
+    pub fn new() -> TestOs {
+        TestOs::default()
+    }
+
+    /// Add some bytes to the Standard Input script.
+    pub fn stdin(mut self, bytes: &[u8]) -> TestOs {
+        self.stdin.push_back(Input::Bytes(bytes.to_vec()));
+        self
+    }
+
+    /// Add a pause to the Standard Input script.
+    ///
+    /// Reads from Standard Input return no data until the pause is over,
+    /// like a user who hasn't pressed a key yet.
+    pub fn delay(mut self, period: Duration) -> TestOs {
+        self.stdin.push_back(Input::Delay(period));
+        self
+    }
+
+    /// Add a file to the filesystem.
+    ///
+    /// Any directories it is in are created too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path isn't valid, or the file name won't fit in a
+    /// directory entry.
+    pub fn file(mut self, path: &str, contents: &[u8]) -> TestOs {
+        let key = MemoryFs::key(path).expect("valid path");
+        self.fs
+            .create_parents(&key)
+            .expect("valid parent directories");
+        self.fs.files.insert(key, contents.to_vec());
+        self
+    }
+
+    /// Add an empty directory to the filesystem.
+    ///
+    /// Any directories it is in are created too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path isn't valid, or the directory name won't fit in a
+    /// directory entry.
+    pub fn dir(mut self, path: &str) -> TestOs {
+        let key = MemoryFs::key(path).expect("valid path");
+        self.fs
+            .create_parents(&key)
+            .expect("valid parent directories");
+        self.fs.dirs.insert(key);
+        self
+    }
+
+    /// Make some of the application's calls fail.
+    ///
+    /// ```
+    /// use neotron_sdk::backend::fault::{Faults, Syscall};
+    /// use neotron_sdk::testing::TestOs;
+    ///
+    /// let output = TestOs::new()
+    ///     .faults(Faults::new().fail_nth(Syscall::Write, 1, neotron_sdk::Error::DeviceSpecific))
+    ///     .run(|| neotron_sdk::stdout().write(b"Hello"));
+    /// assert_eq!(output.exit_code, 1);
+    /// ```
+    pub fn faults(mut self, faults: Faults) -> TestOs {
+        self.faults = faults;
+        self
+    }
+
+    /// Run an application function against this scripted OS.
+    ///
+    /// The function's return value is turned into an exit code in the same
+    /// way as [`entry!`](crate::entry). If it panics, the exit code is
+    /// [`PANIC_EXIT_CODE`].
+    pub fn run<T, F>(self, app: F) -> Output
+    where
+        T: Termination,
+        F: FnOnce() -> T,
+    {
+        let _test_guard = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut os = self;
+        TEST_BACKEND.set_faults(std::mem::take(&mut os.faults));
+        *current() = Some(os);
+        // Safety: we hold `TEST_LOCK`, so no other test is running an
+        // application, and nothing is making OS calls yet.
+        let previous = unsafe { backend::install(&TEST_BACKEND) };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| app().report()));
+        // Safety: the application has finished, and we still hold `TEST_LOCK`.
+        unsafe { backend::restore(previous) };
+        let os = current().take().expect("test OS still installed");
+        Output {
+            exit_code: result.unwrap_or(PANIC_EXIT_CODE),
+            stdout: os.stdout,
+            stderr: os.stderr,
+            fs: os.fs,
+        }
+    }
+
+    fn open(&mut self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        if flags.contains(file::Flags::TRUNCATE) && !flags.contains(file::Flags::WRITE) {
+            return Err(Error::InvalidArg);
+        }
+        let key = MemoryFs::key(path)?;
+        if self.is_open(&key) {
+            return Err(Error::InvalidArg);
+        }
+        if self.fs.dirs.contains(&key) {
+            return Err(Error::InvalidArg);
+        }
+        if !self.fs.files.contains_key(&key) {
+            if !flags.contains(file::Flags::CREATE) {
+                return Err(Error::NotFound);
+            }
+            self.fs.check_new_entry(&key)?;
+            self.fs.files.insert(key.clone(), Vec::new());
+        }
+        if flags.contains(file::Flags::TRUNCATE) {
+            self.fs.files.insert(key.clone(), Vec::new());
+        }
+        let open_file = OpenFile {
+            key,
+            offset: 0,
+            writable: flags.contains(file::Flags::WRITE),
+        };
+        let idx = insert_slot(&mut self.open_files, open_file);
+        let value = u8::try_from(idx + FIRST_FILE_HANDLE).map_err(|_| Error::OutOfMemory)?;
+        Ok(file::Handle::new(value))
+    }
+
+    fn close(&mut self, fd: file::Handle) -> Result<()> {
+        match file_slot(fd).and_then(|idx| self.open_files.get_mut(idx)) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(Error::BadHandle),
+        }
+    }
+
+    fn write(&mut self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        if fd == file::Handle::new_stdout() {
+            self.stdout.extend_from_slice(buffer);
+            return Ok(());
+        }
+        if fd == file::Handle::new_stderr() {
+            self.stderr.extend_from_slice(buffer);
+            return Ok(());
+        }
+        let open_file = self.open_file(fd)?;
+        if !open_file.writable {
+            return Err(Error::FileReadOnly);
+        }
+        let offset = open_file.offset as usize;
+        open_file.offset += buffer.len() as u64;
+        let key = open_file.key.clone();
+        let contents = self.fs.files.get_mut(&key).ok_or(Error::NotFound)?;
+        if contents.len() < offset + buffer.len() {
+            contents.resize(offset + buffer.len(), 0);
+        }
+        contents[offset..offset + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn read(&mut self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        if fd == file::Handle::new_stdin() {
+            return self.read_stdin(buffer);
+        }
+        let open_file = self.open_file(fd)?;
+        let offset = open_file.offset as usize;
+        let key = open_file.key.clone();
+        let contents = self.fs.files.get(&key).ok_or(Error::NotFound)?;
+        if offset >= contents.len() {
+            return Err(Error::EndOfFile);
+        }
+        let count = buffer.len().min(contents.len() - offset);
+        buffer[0..count].copy_from_slice(&contents[offset..offset + count]);
+        self.open_file(fd)?.offset += count as u64;
+        Ok(count)
+    }
+
+    fn read_stdin(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.stdin.front_mut() {
+                None => return Err(Error::EndOfFile),
+                Some(Input::Delay(period)) => {
+                    let until = *self
+                        .delay_until
+                        .get_or_insert_with(|| Instant::now() + *period);
+                    if Instant::now() < until {
+                        return Ok(0);
+                    }
+                    self.delay_until = None;
+                    self.stdin.pop_front();
+                }
+                Some(Input::Bytes(bytes)) => {
+                    let count = buffer.len().min(bytes.len());
+                    buffer[0..count].copy_from_slice(&bytes[0..count]);
+                    bytes.drain(0..count);
+                    if bytes.is_empty() {
+                        self.stdin.pop_front();
+                    }
+                    return Ok(count);
+                }
+            }
+        }
+    }
+
+    fn seek(&mut self, fd: file::Handle, whence: Whence) -> Result<u64> {
+        let open_file = self.open_file(fd)?;
+        let key = open_file.key.clone();
+        let len = self.fs.files.get(&key).ok_or(Error::NotFound)?.len() as u64;
+        let open_file = self.open_file(fd)?;
+        let new_offset = match whence {
+            Whence::Start(position) => Some(position),
+            Whence::Current(offset) => open_file.offset.checked_add_signed(offset),
+            Whence::End => Some(len),
+        };
+        open_file.offset = new_offset.ok_or(Error::InvalidArg)?;
+        Ok(open_file.offset)
+    }
+
+    fn rename(&mut self, old_path: &str, new_path: &str) -> Result<()> {
+        let old_key = MemoryFs::key(old_path)?;
+        let new_key = MemoryFs::key(new_path)?;
+        if self.is_open(&old_key) {
+            return Err(Error::InvalidArg);
+        }
+        if !self.fs.exists(&old_key) {
+            return Err(Error::NotFound);
+        }
+        self.fs.check_new_entry(&new_key)?;
+        if let Some(contents) = self.fs.files.remove(&old_key) {
+            self.fs.files.insert(new_key, contents);
+            return Ok(());
+        }
+        // It's a directory - move everything inside it too
+        let old_prefix = format!("{}/", old_key);
+        self.fs.dirs = std::mem::take(&mut self.fs.dirs)
+            .into_iter()
+            .map(|key| rebase(key, &old_key, &old_prefix, &new_key))
+            .collect();
+        self.fs.files = std::mem::take(&mut self.fs.files)
+            .into_iter()
+            .map(|(key, contents)| (rebase(key, &old_key, &old_prefix, &new_key), contents))
+            .collect();
+        Ok(())
+    }
+
+    fn opendir(&mut self, path: &str) -> Result<dir::Handle> {
+        let key = MemoryFs::key(path)?;
+        if !self.fs.is_dir(&key) {
+            return Err(Error::NotFound);
+        }
+        let entries = self.fs.children(&key).collect::<Result<VecDeque<_>>>()?;
+        let idx = insert_slot(&mut self.open_dirs, OpenDir { entries });
+        let value = u8::try_from(idx).map_err(|_| Error::OutOfMemory)?;
+        Ok(dir::Handle::new(value))
+    }
+
+    fn closedir(&mut self, dir: dir::Handle) -> Result<()> {
+        match self.open_dirs.get_mut(usize::from(dir.value())) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(Error::BadHandle),
+        }
+    }
+
+    fn readdir(&mut self, dir: dir::Handle) -> Result<dir::Entry> {
+        match self.open_dirs.get_mut(usize::from(dir.value())) {
+            Some(Some(open_dir)) => open_dir.entries.pop_front().ok_or(Error::EndOfFile),
+            _ => Err(Error::BadHandle),
+        }
+    }
+
+    fn stat(&mut self, path: &str) -> Result<file::Stat> {
+        let key = MemoryFs::key(path)?;
+        self.fs.stat(&key)
+    }
+
+    fn fstat(&mut self, fd: file::Handle) -> Result<file::Stat> {
+        let key = self.open_file(fd)?.key.clone();
+        self.fs.stat(&key)
+    }
+
+    fn deletefile(&mut self, path: &str) -> Result<()> {
+        let key = MemoryFs::key(path)?;
+        if self.is_open(&key) {
+            return Err(Error::InvalidArg);
+        }
+        self.fs
+            .files
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(Error::NotFound)
+    }
+
+    fn deletedir(&mut self, path: &str) -> Result<()> {
+        let key = MemoryFs::key(path)?;
+        if !self.fs.dirs.contains(&key) {
+            // Either it doesn't exist, or it's a root directory
+            return Err(Error::NotFound);
+        }
+        if self.fs.children(&key).next().is_some() {
+            return Err(Error::InvalidArg);
+        }
+        self.fs.dirs.remove(&key);
+        Ok(())
+    }
+
+    /// Is this file currently open?
+    fn is_open(&self, key: &str) -> bool {
+        self.open_files.iter().flatten().any(|f| f.key == key)
+    }
+
+    /// Look up an open file.
+    fn open_file(&mut self, fd: file::Handle) -> Result<&mut OpenFile> {
+        file_slot(fd)
+            .and_then(|idx| self.open_files.get_mut(idx))
+            .and_then(|slot| slot.as_mut())
+            .ok_or(Error::BadHandle)
+    }
+}
+
+/// The [`Backend`] which passes calls on to the [`TestOs`] in [`CURRENT`].
+struct CurrentOs;
+
+impl Backend for CurrentOs {
+    fn open(&self, path: &str, flags: file::Flags) -> Result<file::Handle> {
+        with_os(|os| os.open(path, flags))
+    }
+
+    fn close(&self, fd: file::Handle) -> Result<()> {
+        with_os(|os| os.close(fd))
+    }
+
+    fn write(&self, fd: file::Handle, buffer: &[u8]) -> Result<()> {
+        with_os(|os| os.write(fd, buffer))
+    }
+
+    fn read(&self, fd: file::Handle, buffer: &mut [u8]) -> Result<usize> {
+        with_os(|os| os.read(fd, buffer))
+    }
+
+    fn seek_set(&self, fd: file::Handle, position: u64) -> Result<()> {
+        with_os(|os| os.seek(fd, Whence::Start(position)).map(|_| ()))
+    }
+
+    fn seek_cur(&self, fd: file::Handle, offset: i64) -> Result<u64> {
+        with_os(|os| os.seek(fd, Whence::Current(offset)))
+    }
+
+    fn seek_end(&self, fd: file::Handle) -> Result<u64> {
+        with_os(|os| os.seek(fd, Whence::End))
+    }
+
+    fn rename(&self, old_path: &str, new_path: &str) -> Result<()> {
+        with_os(|os| os.rename(old_path, new_path))
+    }
+
+    fn opendir(&self, path: &str) -> Result<dir::Handle> {
+        with_os(|os| os.opendir(path))
+    }
+
+    fn closedir(&self, dir: dir::Handle) -> Result<()> {
+        with_os(|os| os.closedir(dir))
+    }
+
+    fn readdir(&self, dir: dir::Handle) -> Result<dir::Entry> {
+        with_os(|os| os.readdir(dir))
+    }
+
+    fn stat(&self, path: &str) -> Result<file::Stat> {
+        with_os(|os| os.stat(path))
+    }
+
+    fn fstat(&self, fd: file::Handle) -> Result<file::Stat> {
+        with_os(|os| os.fstat(fd))
+    }
+
+    fn deletefile(&self, path: &str) -> Result<()> {
+        with_os(|os| os.deletefile(path))
+    }
+
+    fn deletedir(&self, path: &str) -> Result<()> {
+        with_os(|os| os.deletedir(path))
+    }
+}
+
+/// What an application run under [`TestOs`] did.
+#[derive(Debug)]
+pub struct Output {
+    /// The exit code it returned.
+    pub exit_code: i32,
+    /// Everything it wrote to Standard Output.
+    pub stdout: Vec<u8>,
+    /// Everything it wrote to Standard Error.
+    pub stderr: Vec<u8>,
+    fs: MemoryFs,
+}
+
+impl Output {
+    /// Get Standard Output as a string.
+    ///
+    /// Any invalid UTF-8 is replaced with `U+FFFD`.
+    pub fn stdout_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    /// Get Standard Error as a string.
+    ///
+    /// Any invalid UTF-8 is replaced with `U+FFFD`.
+    pub fn stderr_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+
+    /// Get the contents of a file, as the application left it.
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        let key = MemoryFs::key(path).ok()?;
+        self.fs.files.get(&key).map(|v| v.as_slice())
+    }
+
+    /// Does this directory exist, now the application has finished?
+    pub fn dir_exists(&self, path: &str) -> bool {
+        MemoryFs::key(path).is_ok_and(|key| self.fs.is_dir(&key))
+    }
+}
+
+/// One step in the Standard Input script.
+#[derive(Debug)]
+enum Input {
+    Bytes(Vec<u8>),
+    Delay(Duration),
+}
+
+/// Where to seek from.
+#[derive(Debug, Copy, Clone)]
+enum Whence {
+    Start(u64),
+    Current(i64),
+    End,
+}
+
+/// A file the application has open.
+#[derive(Debug)]
+struct OpenFile {
+    key: String,
+    offset: u64,
+    writable: bool,
+}
+
+/// A directory the application has open.
+///
+/// We take a copy of the entries when it is opened.
+#[derive(Debug)]
+struct OpenDir {
+    entries: VecDeque<dir::Entry>,
+}
+
+/// A filesystem held in memory.
+///
+/// Paths are stored normalised and in upper-case, so that look-ups are
+/// case-insensitive like on a FAT disk. Root directories (like `HD0:/`, or
+/// the empty path for relative paths) always exist.
+#[derive(Debug, Default)]
+struct MemoryFs {
+    files: BTreeMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+}
+
+impl MemoryFs {
+    /// Turn a path into the key we store it under.
+    fn key(path: &str) -> Result<String> {
+        let path: PathBuf = path.parse()?;
+        Ok(path.as_str().to_ascii_uppercase())
+    }
+
+    /// Is this a directory (including a root directory)?
+    fn is_dir(&self, key: &str) -> bool {
+        parent_of(key).is_none() || self.dirs.contains(key)
+    }
+
+    /// Does this file or directory exist?
+    fn exists(&self, key: &str) -> bool {
+        self.files.contains_key(key) || self.is_dir(key)
+    }
+
+    /// Check we can create a new file or directory with this key.
+    fn check_new_entry(&self, key: &str) -> Result<()> {
+        if self.exists(key) {
+            return Err(Error::InvalidArg);
+        }
+        let (parent, name) = split_key(key).ok_or(Error::InvalidPath)?;
+        if name.len() > neotron_api::MAX_FILENAME_LEN {
+            return Err(Error::InvalidPath);
+        }
+        if !self.is_dir(parent) {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Create all the directories above this key.
+    fn create_parents(&mut self, key: &str) -> Result<()> {
+        let Some((parent, name)) = split_key(key) else {
+            return Ok(());
+        };
+        if name.len() > neotron_api::MAX_FILENAME_LEN {
+            return Err(Error::InvalidPath);
+        }
+        if !self.is_dir(parent) {
+            self.create_parents(parent)?;
+            self.dirs.insert(parent.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Get the directory entries for everything in this directory.
+    fn children<'a>(&'a self, key: &'a str) -> impl Iterator<Item = Result<dir::Entry>> + 'a {
+        let dirs = self.dirs.iter().filter(move |k| parent_of(k) == Some(key));
+        let files = self.files.keys().filter(move |k| parent_of(k) == Some(key));
+        dirs.chain(files).map(|k| {
+            let (_parent, name) = split_key(k).ok_or(Error::InvalidPath)?;
+            let mut entry = dir::Entry {
+                name: [0u8; neotron_api::MAX_FILENAME_LEN],
+                properties: self.stat(k)?,
+            };
+            entry.name[0..name.len()].copy_from_slice(name.as_bytes());
+            Ok(entry)
+        })
+    }
+
+    /// Get information about a file or directory.
+    fn stat(&self, key: &str) -> Result<file::Stat> {
+        let (file_size, attr) = if let Some(contents) = self.files.get(key) {
+            (contents.len() as u64, file::Attributes::empty())
+        } else if self.is_dir(key) {
+            (0, file::Attributes::DIRECTORY)
+        } else {
+            return Err(Error::NotFound);
+        };
+        let time = file::Time {
+            year_since_1970: 0,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        };
+        Ok(file::Stat {
+            file_size,
+            ctime: time,
+            mtime: time,
+            attr,
+        })
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Get the OS for the application that is currently running.
+fn current() -> MutexGuard<'static, Option<TestOs>> {
+    CURRENT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Call a function on the current OS.
+fn with_os<T>(f: impl FnOnce(&mut TestOs) -> Result<T>) -> Result<T> {
+    match current().as_mut() {
+        Some(os) => f(os),
+        None => Err(Error::Unimplemented),
+    }
+}
+
+/// Convert a file handle into an index into [`TestOs::open_files`].
+fn file_slot(fd: file::Handle) -> Option<usize> {
+    usize::from(fd.value()).checked_sub(FIRST_FILE_HANDLE)
+}
+
+/// Put an item in the first free slot, returning its index.
+fn insert_slot<T>(slots: &mut Vec<Option<T>>, item: T) -> usize {
+    match slots.iter().position(|slot| slot.is_none()) {
+        Some(idx) => {
+            slots[idx] = Some(item);
+            idx
+        }
+        None => {
+            slots.push(Some(item));
+            slots.len() - 1
+        }
+    }
+}
+
+/// Split a key into the parent directory and the name.
+///
+/// Returns `None` for a root directory.
+fn split_key(key: &str) -> Option<(&str, &str)> {
+    let parent = parent_of(key)?;
+    let name = key[parent.len()..].trim_start_matches('/');
+    Some((parent, name))
+}
+
+/// Get the key for the directory that this key is in.
+///
+/// Returns `None` for a root directory.
+fn parent_of(key: &str) -> Option<&str> {
+    let path: PathBuf = key.parse().ok()?;
+    let parent_len = path.parent()?.len();
+    Some(&key[0..parent_len])
+}
+
+/// Move a key from one directory to another, if it is inside `old_key`.
+fn rebase(key: String, old_key: &str, old_prefix: &str, new_key: &str) -> String {
+    if key == old_key {
+        new_key.to_owned()
+    } else if let Some(rest) = key.strip_prefix(old_prefix) {
+        format!("{}/{}", new_key, rest)
+    } else {
+        key
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{path::Path, File, Flags, ReadDir};
+
+    #[test]
+    fn stdin_script_waits_for_delays() {
+        let mut zero_reads = 0;
+        let mut read = Vec::new();
+        let output = TestOs::new()
+            .stdin(b"a")
+            .delay(Duration::from_millis(20))
+            .stdin(b"b")
+            .run(|| loop {
+                let mut buffer = [0u8; 4];
+                match crate::stdin().read(&mut buffer) {
+                    Ok(0) => zero_reads += 1,
+                    Ok(n) => read.extend_from_slice(&buffer[0..n]),
+                    Err(Error::EndOfFile) => break Ok::<(), Error>(()),
+                    Err(e) => break Err(e),
+                }
+            });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(read, b"ab");
+        assert!(zero_reads > 0);
+    }
+
+    #[test]
+    fn files_can_be_written_and_read_back() {
+        let mut contents = Vec::new();
+        let output = TestOs::new().file("HD0:/OLD.TXT", b"Hello").run(|| {
+            let new = File::open(
+                Path::new("HD0:/DOCS/NEW.TXT")?,
+                Flags::WRITE | Flags::CREATE,
+            );
+            assert_eq!(new.err(), Some(Error::NotFound));
+            let new = File::open(Path::new("HD0:/NEW.TXT")?, Flags::WRITE | Flags::CREATE)?;
+            new.write(b"world")?;
+            let old = File::open(Path::new("hd0:/old.txt")?, Flags::empty())?;
+            let mut buffer = [0u8; 8];
+            old.seek_set(1)?;
+            let n = old.read(&mut buffer)?;
+            contents.extend_from_slice(&buffer[0..n]);
+            Ok::<(), Error>(())
+        });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(contents, b"ello");
+        assert_eq!(output.file("HD0:/NEW.TXT"), Some(&b"world"[..]));
+        assert_eq!(output.file("HD0:/OLD.TXT"), Some(&b"Hello"[..]));
+    }
+
+    #[test]
+    fn directories_list_their_contents() {
+        let mut names = Vec::new();
+        let output = TestOs::new()
+            .file("HD0:/DOCS/A.TXT", b"")
+            .dir("HD0:/DOCS/SUB")
+            .run(|| {
+                for entry in ReadDir::open(Path::new("HD0:/DOCS")?)? {
+                    names.push(crate::entry_name(&entry?).to_owned());
+                }
+                Ok::<(), Error>(())
+            });
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(names, ["SUB", "A.TXT"]);
+        assert!(output.dir_exists("HD0:/DOCS/SUB"));
+    }
+
+    #[test]
+    fn renaming_a_directory_moves_its_contents() {
+        let output = TestOs::new()
+            .file("HD0:/OLD/SUB/A.TXT", b"a")
+            .run(|| File::rename(Path::new("HD0:/OLD")?, Path::new("HD0:/NEW")?));
+        assert_eq!(output.exit_code, 0);
+        assert!(!output.dir_exists("HD0:/OLD"));
+        assert!(output.dir_exists("HD0:/NEW/SUB"));
+        assert_eq!(output.file("HD0:/NEW/SUB/A.TXT"), Some(&b"a"[..]));
+    }
+
+    #[test]
+    fn panics_give_the_panic_exit_code() {
+        let output = TestOs::new().run(|| -> Result<()> {
+            crate::stdout().write(b"before")?;
+            panic!("oh no");
+        });
+        assert_eq!(output.exit_code, PANIC_EXIT_CODE);
+        assert_eq!(output.stdout_str(), "before");
+        // The next application still gets a working OS
+        let output = TestOs::new().run(|| crate::stdout().write(b"after"));
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout_str(), "after");
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,612 @@
+//! Measuring time
+//!
+//! Provides [`Instant`], which you can use to measure how long things take.
+//!
+//! On UNIX and Windows this uses `std::time::Instant`. On Neotron OS it counts
+//! CPU cycles, using the DWT cycle counter or SysTick, and assumes the CPU
+//! runs at the clock speed given by the `NEOTRON_CPU_HZ` environment variable
+//! at build time. See [`Instant`] for the longest interval it can measure.
+//!
+//! Also provides calendar functions for the [`Time`] values you get back
+//! from [`File::stat`](crate::File::stat), through the [`TimeExt`] trait, and
+//! the [`Iso8601`] and [`DosDateTime`] adapters for printing and parsing
+//! them.
+
+// ============================================================================
+// Imports
+// ============================================================================
+
+use core::time::Duration;
+
+use crate::Error;
+
+pub use neotron_api::file::Time;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// How many times a second the value from [`cycles`] goes up.
+#[cfg(all(target_os = "none", target_arch = "arm"))]
+const CYCLES_PER_SECOND: u32 = crate::cortex_m::CPU_HZ;
+
+/// How many times a second the value from [`cycles`] goes up.
+#[cfg(all(target_os = "none", not(target_arch = "arm")))]
+const CYCLES_PER_SECOND: u32 = 1;
+
+/// The number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The latest Unix timestamp a [`Time`] can hold (2225-12-31 23:59:59).
+const MAX_TIMESTAMP: u64 = 8_078_572_799;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A measurement of a monotonically increasing clock.
+///
+/// Like `std::time::Instant`, these are only useful for comparing with each
+/// other.
+///
+/// On Neotron OS, the hardware counters we use wrap around quite quickly. We
+/// handle that, as long as something calls [`Instant::now`] (or
+/// [`Instant::elapsed`]) at least once per wrap. If nothing does, time is
+/// lost and we can't tell, so that is the longest interval you can measure:
+///
+/// * On a CPU with a DWT cycle counter (like the Cortex-M3, M4 or M7), 2^32
+///   CPU cycles - about 32 seconds at 133 MHz.
+/// * On a CPU without one (like the Cortex-M0+), one SysTick period. That's
+///   2^24 CPU cycles (about 126 ms at 133 MHz) if the OS isn't using
+///   SysTick, in which case we turn it on and turn it off again when the
+///   application exits. If the OS is using SysTick, it's whatever period the
+///   OS has set. If that's less than 10 ms, or SysTick isn't counting CPU
+///   cycles, we can't measure time at all, and [`Instant::now`] returns
+///   `None`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    #[cfg(target_os = "none")]
+    cycles: u64,
+    #[cfg(not(target_os = "none"))]
+    inner: std::time::Instant,
+}
+
+impl Instant {
+    /// Get an `Instant` representing now.
+    ///
+    /// Returns `None` if we can't measure time on this system.
+    #[cfg(target_os = "none")]
+    pub fn now() -> Option<Instant> {
+        cycles().map(|cycles| Instant { cycles })
+    }
+
+    /// Get an `Instant` representing now.
+    ///
+    /// Returns `None` if we can't measure time on this system, which never
+    /// happens on UNIX or Windows.
+    #[cfg(not(target_os = "none"))]
+    pub fn now() -> Option<Instant> {
+        Some(Instant {
+            inner: std::time::Instant::now(),
+        })
+    }
+
+    /// How much time has passed between `earlier` and this `Instant`.
+    ///
+    /// Returns zero if `earlier` is later than this `Instant`.
+    #[cfg(target_os = "none")]
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        let cycles = self.cycles.saturating_sub(earlier.cycles);
+        let hz = u64::from(CYCLES_PER_SECOND);
+        let secs = cycles / hz;
+        let nanos = (cycles % hz) * 1_000_000_000 / hz;
+        Duration::new(secs, nanos as u32)
+    }
+
+    /// How much time has passed between `earlier` and this `Instant`.
+    ///
+    /// Returns zero if `earlier` is later than this `Instant`.
+    #[cfg(not(target_os = "none"))]
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.inner.saturating_duration_since(earlier.inner)
+    }
+
+    /// How much time has passed since this `Instant`.
+    ///
+    /// Returns zero if we can no longer measure time (because the OS has
+    /// changed SysTick since this `Instant` was taken).
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().map_or(Duration::ZERO, |now| now.duration_since(*self))
+    }
+}
+
+impl core::ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A day of the week.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// Get the English name of this day.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Weekday::Monday => "Monday",
+            Weekday::Tuesday => "Tuesday",
+            Weekday::Wednesday => "Wednesday",
+            Weekday::Thursday => "Thursday",
+            Weekday::Friday => "Friday",
+            Weekday::Saturday => "Saturday",
+            Weekday::Sunday => "Sunday",
+        }
+    }
+}
+
+/// Calendar functions for [`Time`].
+///
+/// A [`Time`] counts years from 1970, and months and days from zero, so it
+/// can represent dates from 1970 up to the end of 2225.
+pub trait TimeExt: Sized {
+    /// Convert a Unix timestamp (seconds since 1970-01-01 00:00:00) into a
+    /// [`Time`].
+    ///
+    /// Returns `None` if the timestamp is after the end of 2225.
+    fn from_unix_timestamp(timestamp: u64) -> Option<Self>;
+
+    /// Convert this [`Time`] into a Unix timestamp.
+    ///
+    /// Returns `None` if this isn't a valid date and time.
+    fn to_unix_timestamp(&self) -> Option<u64>;
+
+    /// Is this a valid date and time?
+    ///
+    /// For example, the 30th of February is not valid.
+    fn is_valid(&self) -> bool;
+
+    /// Get the day of the week.
+    ///
+    /// Returns `None` if this isn't a valid date and time.
+    fn weekday(&self) -> Option<Weekday>;
+}
+
+impl TimeExt for Time {
+    fn from_unix_timestamp(timestamp: u64) -> Option<Time> {
+        if timestamp > MAX_TIMESTAMP {
+            return None;
+        }
+        let days = timestamp / SECONDS_PER_DAY;
+        let seconds = timestamp % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days as u32);
+        Some(Time {
+            year_since_1970: (year - 1970) as u8,
+            zero_indexed_month: (month - 1) as u8,
+            zero_indexed_day: (day - 1) as u8,
+            hours: (seconds / 3600) as u8,
+            minutes: ((seconds / 60) % 60) as u8,
+            seconds: (seconds % 60) as u8,
+        })
+    }
+
+    fn to_unix_timestamp(&self) -> Option<u64> {
+        if !self.is_valid() {
+            return None;
+        }
+        let days = days_from_civil(
+            1970 + u32::from(self.year_since_1970),
+            u32::from(self.zero_indexed_month) + 1,
+            u32::from(self.zero_indexed_day) + 1,
+        );
+        Some(
+            (u64::from(days) * SECONDS_PER_DAY)
+                + (u64::from(self.hours) * 3600)
+                + (u64::from(self.minutes) * 60)
+                + u64::from(self.seconds),
+        )
+    }
+
+    fn is_valid(&self) -> bool {
+        let year = 1970 + u32::from(self.year_since_1970);
+        let month = u32::from(self.zero_indexed_month) + 1;
+        month <= 12
+            && u32::from(self.zero_indexed_day) < days_in_month(year, month)
+            && self.hours < 24
+            && self.minutes < 60
+            && self.seconds < 60
+    }
+
+    fn weekday(&self) -> Option<Weekday> {
+        let days = self.to_unix_timestamp()? / SECONDS_PER_DAY;
+        // 1970-01-01 was a Thursday
+        let weekday = match days % 7 {
+            0 => Weekday::Thursday,
+            1 => Weekday::Friday,
+            2 => Weekday::Saturday,
+            3 => Weekday::Sunday,
+            4 => Weekday::Monday,
+            5 => Weekday::Tuesday,
+            _ => Weekday::Wednesday,
+        };
+        Some(weekday)
+    }
+}
+
+/// Prints and parses a [`Time`] in ISO 8601 format, like
+/// `2024-05-19T14:30:00`.
+///
+/// When parsing, a space is also accepted between the date and the time, and
+/// the time may be left off entirely.
+///
+/// ```
+/// use neotron_sdk::time::Iso8601;
+/// let time: Iso8601 = "2024-05-19T14:30:00".parse().unwrap();
+/// assert_eq!(format!("{}", time), "2024-05-19T14:30:00");
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Iso8601(pub Time);
+
+impl core::fmt::Display for Iso8601 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            1970 + u32::from(self.0.year_since_1970),
+            u32::from(self.0.zero_indexed_month) + 1,
+            u32::from(self.0.zero_indexed_day) + 1,
+            self.0.hours,
+            self.0.minutes,
+            self.0.seconds
+        )
+    }
+}
+
+impl core::str::FromStr for Iso8601 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Iso8601, Error> {
+        let (date, time) = match s.split_once(['T', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+        let mut date_parts = date.split('-');
+        let year = parse_field(date_parts.next())?;
+        let month = parse_field(date_parts.next())?;
+        let day = parse_field(date_parts.next())?;
+        if date_parts.next().is_some() {
+            return Err(Error::InvalidArg);
+        }
+        let (hours, minutes, seconds) = match time {
+            Some(time) => parse_time(time)?,
+            None => (0, 0, 0),
+        };
+        make_time(year, month, day, hours, minutes, seconds).map(Iso8601)
+    }
+}
+
+/// Prints and parses a [`Time`] in the format MS-DOS uses, like
+/// `19/05/2024 14:30`.
+///
+/// When parsing, seconds are also accepted (e.g. `19/05/2024 14:30:15`).
+///
+/// ```
+/// use neotron_sdk::time::DosDateTime;
+/// let time: DosDateTime = "19/05/2024 14:30".parse().unwrap();
+/// assert_eq!(format!("{}", time), "19/05/2024 14:30");
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DosDateTime(pub Time);
+
+impl core::fmt::Display for DosDateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02}/{:02}/{:04} {:02}:{:02}",
+            u32::from(self.0.zero_indexed_day) + 1,
+            u32::from(self.0.zero_indexed_month) + 1,
+            1970 + u32::from(self.0.year_since_1970),
+            self.0.hours,
+            self.0.minutes
+        )
+    }
+}
+
+impl core::str::FromStr for DosDateTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DosDateTime, Error> {
+        let (date, time) = s.trim().split_once(' ').ok_or(Error::InvalidArg)?;
+        let mut date_parts = date.split('/');
+        let day = parse_field(date_parts.next())?;
+        let month = parse_field(date_parts.next())?;
+        let year = parse_field(date_parts.next())?;
+        if date_parts.next().is_some() {
+            return Err(Error::InvalidArg);
+        }
+        let (hours, minutes, seconds) = parse_time(time.trim())?;
+        make_time(year, month, day, hours, minutes, seconds).map(DosDateTime)
+    }
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Is this a leap year in the Gregorian calendar?
+const fn is_leap_year(year: u32) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+/// How many days are there in the given (one-indexed) month?
+const fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Convert a date into days since 1970-01-01.
+///
+/// Uses Howard Hinnant's `days_from_civil` algorithm, simplified for dates
+/// after 1970.
+const fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Convert days since 1970-01-01 into a (year, month, day) date.
+///
+/// Uses Howard Hinnant's `civil_from_days` algorithm, simplified for dates
+/// after 1970.
+const fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    let year = if month <= 2 { year + 1 } else { year };
+    (year, month, day)
+}
+
+/// Parse one numeric field of a date or time.
+fn parse_field(field: Option<&str>) -> Result<u32, Error> {
+    let field = field.ok_or(Error::InvalidArg)?;
+    if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidArg);
+    }
+    field.parse().map_err(|_| Error::InvalidArg)
+}
+
+/// Parse a time, like `14:30` or `14:30:15`.
+fn parse_time(time: &str) -> Result<(u32, u32, u32), Error> {
+    let mut time_parts = time.split(':');
+    let hours = parse_field(time_parts.next())?;
+    let minutes = parse_field(time_parts.next())?;
+    let seconds = match time_parts.next() {
+        Some(seconds) => parse_field(Some(seconds))?,
+        None => 0,
+    };
+    if time_parts.next().is_some() {
+        return Err(Error::InvalidArg);
+    }
+    Ok((hours, minutes, seconds))
+}
+
+/// Build a [`Time`] from calendar values, checking they are valid.
+fn make_time(
+    year: u32,
+    month: u32,
+    day: u32,
+    hours: u32,
+    minutes: u32,
+    seconds: u32,
+) -> Result<Time, Error> {
+    if !(1970..=2225).contains(&year)
+        || !(1..=12).contains(&month)
+        || day == 0
+        || day > 31
+        || hours > 23
+        || minutes > 59
+        || seconds > 59
+    {
+        return Err(Error::InvalidArg);
+    }
+    let time = Time {
+        year_since_1970: (year - 1970) as u8,
+        zero_indexed_month: (month - 1) as u8,
+        zero_indexed_day: (day - 1) as u8,
+        hours: hours as u8,
+        minutes: minutes as u8,
+        seconds: seconds as u8,
+    };
+    if time.is_valid() {
+        Ok(time)
+    } else {
+        Err(Error::InvalidArg)
+    }
+}
+
+/// Count CPU cycles.
+#[cfg(all(target_os = "none", target_arch = "arm"))]
+fn cycles() -> Option<u64> {
+    crate::cortex_m::cycles()
+}
+
+/// Count CPU cycles.
+///
+/// We don't know how to do that on this CPU.
+#[cfg(all(target_os = "none", not(target_arch = "arm")))]
+fn cycles() -> Option<u64> {
+    None
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(year: u32, month: u32, day: u32, hours: u8, minutes: u8, seconds: u8) -> Time {
+        Time {
+            year_since_1970: (year - 1970) as u8,
+            zero_indexed_month: (month - 1) as u8,
+            zero_indexed_day: (day - 1) as u8,
+            hours,
+            minutes,
+            seconds,
+        }
+    }
+
+    #[test]
+    fn unix_timestamps_round_trip() {
+        for timestamp in [0, 86_399, 86_400, 951_782_400, 1_716_129_000, MAX_TIMESTAMP] {
+            let time = Time::from_unix_timestamp(timestamp).unwrap();
+            assert_eq!(time.to_unix_timestamp(), Some(timestamp));
+        }
+        assert_eq!(
+            Time::from_unix_timestamp(0),
+            Some(time(1970, 1, 1, 0, 0, 0))
+        );
+        assert_eq!(
+            Time::from_unix_timestamp(1_716_129_000),
+            Some(time(2024, 5, 19, 14, 30, 0))
+        );
+    }
+
+    #[test]
+    fn the_end_of_2225_is_the_limit() {
+        assert_eq!(
+            Time::from_unix_timestamp(MAX_TIMESTAMP),
+            Some(time(2225, 12, 31, 23, 59, 59))
+        );
+        assert_eq!(Time::from_unix_timestamp(MAX_TIMESTAMP + 1), None);
+        assert!("2225-12-31T23:59:59".parse::<Iso8601>().is_ok());
+        assert_eq!("2226-01-01".parse::<Iso8601>(), Err(Error::InvalidArg));
+    }
+
+    #[test]
+    fn leap_years() {
+        assert!(time(2000, 2, 29, 0, 0, 0).is_valid());
+        assert!(time(2024, 2, 29, 0, 0, 0).is_valid());
+        assert!(!time(2100, 2, 29, 0, 0, 0).is_valid());
+        assert!(!time(2023, 2, 29, 0, 0, 0).is_valid());
+        assert_eq!(
+            time(2000, 2, 29, 0, 0, 0).to_unix_timestamp(),
+            Some(951_782_400)
+        );
+        assert_eq!(time(2100, 2, 29, 0, 0, 0).to_unix_timestamp(), None);
+    }
+
+    #[test]
+    fn invalid_fields() {
+        assert!(!time(2024, 4, 31, 0, 0, 0).is_valid());
+        assert!(!time(2024, 1, 1, 24, 0, 0).is_valid());
+        assert!(!time(2024, 1, 1, 0, 60, 0).is_valid());
+        assert!(!time(2024, 1, 1, 0, 0, 60).is_valid());
+        let mut bad_month = time(2024, 1, 1, 0, 0, 0);
+        bad_month.zero_indexed_month = 12;
+        assert!(!bad_month.is_valid());
+        assert_eq!(bad_month.weekday(), None);
+    }
+
+    #[test]
+    fn weekdays() {
+        assert_eq!(time(1970, 1, 1, 0, 0, 0).weekday(), Some(Weekday::Thursday));
+        assert_eq!(time(2000, 1, 1, 0, 0, 0).weekday(), Some(Weekday::Saturday));
+        assert_eq!(
+            time(2024, 5, 19, 23, 59, 59).weekday(),
+            Some(Weekday::Sunday)
+        );
+        assert_eq!(time(2024, 5, 20, 0, 0, 0).weekday(), Some(Weekday::Monday));
+        assert_eq!(Weekday::Wednesday.name(), "Wednesday");
+    }
+
+    #[test]
+    fn iso8601_parsing() {
+        let parsed: Iso8601 = "2024-05-19 14:30:15".parse().unwrap();
+        assert_eq!(parsed.0, time(2024, 5, 19, 14, 30, 15));
+        let parsed: Iso8601 = "2024-05-19".parse().unwrap();
+        assert_eq!(parsed.0, time(2024, 5, 19, 0, 0, 0));
+        for bad in [
+            "2024-02-30",
+            "2023-02-29",
+            "2024-05-19T24:00",
+            "2024-05-19T12:60",
+            "2024-05-19-01",
+            "2024-05-19T12:00:00:00",
+            "1969-12-31",
+            "2024-5-x",
+            "2024-+5-19",
+            "",
+        ] {
+            assert_eq!(bad.parse::<Iso8601>(), Err(Error::InvalidArg), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn dos_date_time_parsing() {
+        let parsed: DosDateTime = " 19/05/2024 14:30:15 ".parse().unwrap();
+        assert_eq!(parsed.0, time(2024, 5, 19, 14, 30, 15));
+        for bad in [
+            "30/02/2024 00:00",
+            "19/05/2024",
+            "19/05/2024 24:00",
+            "19/05/2024/01 00:00",
+            "31/12/1969 00:00",
+        ] {
+            assert_eq!(
+                bad.parse::<DosDateTime>(),
+                Err(Error::InvalidArg),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn printing_out_of_range_fields_does_not_panic() {
+        let time = Time {
+            year_since_1970: 255,
+            zero_indexed_month: 255,
+            zero_indexed_day: 255,
+            hours: 255,
+            minutes: 255,
+            seconds: 255,
+        };
+        assert_eq!(format!("{}", Iso8601(time)), "2225-256-256T255:255:255");
+        assert_eq!(format!("{}", DosDateTime(time)), "256/256/2225 255:255");
+    }
+}
+
+// ============================================================================
+// End of File
+// ============================================================================